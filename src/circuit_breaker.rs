@@ -0,0 +1,119 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Fail fast after repeated upstream failures instead of paying for the requirements and the
+/// proof of work of requests that are bound to fail.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    failures: u32,
+    window_start: Option<Instant>,
+    open_until: Option<Instant>,
+    /// A probe was let through after the cooldown and has not finished yet.
+    probing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// The cooldown is over, the next request or the one in flight probes the upstream.
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half-open",
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            cooldown,
+            state: Default::default(),
+        }
+    }
+
+    /// Check whether a request may go upstream. Once the cooldown is over a single request is
+    /// let through as a probe, and the circuit stays open for the others until it succeeds.
+    pub fn allow(&self) -> std::result::Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(open_until) => {
+                let now = Instant::now();
+                if now < open_until {
+                    Err(open_until - now)
+                } else {
+                    state.open_until = Some(now + self.cooldown);
+                    state.probing = true;
+                    info!("Circuit breaker is half-open, probing the upstream");
+                    Ok(())
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The current state, and how long requests keep being refused.
+    pub fn state(&self) -> (CircuitState, Duration) {
+        let state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return (CircuitState::Closed, Duration::ZERO);
+        };
+        let retry_after = open_until.saturating_duration_since(Instant::now());
+        if state.probing || retry_after.is_zero() {
+            (CircuitState::HalfOpen, retry_after)
+        } else {
+            (CircuitState::Open, retry_after)
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            info!("Circuit breaker is closed, the upstream recovered");
+        }
+        *state = State::default();
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state.open_until.is_some() {
+            state.open_until = Some(now + self.cooldown);
+            state.probing = false;
+            return;
+        }
+        let in_window =
+            matches!(state.window_start, Some(v) if now.duration_since(v) <= self.window);
+        if !in_window {
+            state.window_start = Some(now);
+            state.failures = 0;
+        }
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            warn!(
+                "Circuit breaker is open after {} consecutive upstream failures, failing fast for {}s",
+                state.failures,
+                self.cooldown.as_secs()
+            );
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
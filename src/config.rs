@@ -0,0 +1,720 @@
+use crate::proof::ProofFormat;
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use http::HeaderValue;
+use ipnet::IpNet;
+use std::{
+    collections::HashMap,
+    env, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+pub const PORT: u16 = 3040;
+pub const MAX_MESSAGES: usize = 200;
+pub const MAX_BATCH_SIZE: usize = 20;
+pub const COMPLETION_ID_PREFIX: &str = "chatcmpl-";
+pub const FALLBACK_MESSAGE: &str =
+    "The service is temporarily unavailable, please try again in a few minutes.";
+pub const UPSTREAM_BASE_URL: &str = "https://chat.openai.com";
+pub const CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const MAX_UPSTREAM_TIMEOUT_MS: u64 = 600000;
+pub const HEADER_READ_TIMEOUT_SECS: u64 = 30;
+pub const BODY_READ_TIMEOUT_SECS: u64 = 30;
+pub const CIRCUIT_BREAKER_WINDOW_SECS: u64 = 60;
+pub const CIRCUIT_BREAKER_COOLDOWN_SECS: u64 = 30;
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_QUEUE_DEPTH: usize = 100;
+pub const CONNECTION_RATE_WINDOW_SECS: u64 = 60;
+pub const PROMPT_HOOK_TIMEOUT_SECS: u64 = 5;
+pub const LOG_MAX_FILES: usize = 5;
+pub const MODEL_CONTEXT_WINDOW: u64 = 8192;
+pub const MODEL_MAX_OUTPUT_TOKENS: u64 = 4096;
+
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+/// Keeps the ids, with their 16 random characters, within 64 characters.
+const MAX_COMPLETION_ID_PREFIX_LEN: usize = 48;
+
+#[derive(Debug)]
+pub struct Config {
+    pub host: IpAddr,
+    pub port: u16,
+    pub base_path: String,
+    pub header_read_timeout: Duration,
+    pub body_read_timeout: Duration,
+    pub shutdown_drain: Duration,
+    pub max_body_size: usize,
+    pub log_file: Option<String>,
+    pub log_stdout: bool,
+    pub log_max_size: Option<u64>,
+    pub log_max_files: usize,
+    pub upstream_base_url: String,
+    pub proxy: Option<String>,
+    pub connect_timeout: Duration,
+    pub upstream_ip_family: Option<IpFamily>,
+    pub upstream_min_tls: Option<TlsVersion>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub upstream_http2: bool,
+    pub disable_pow: bool,
+    pub pow_threads: usize,
+    pub pow_format: ProofFormat,
+    pub selftest: bool,
+    pub auto_resume: bool,
+    pub max_frame_size: usize,
+    pub max_upstream_bytes: Option<usize>,
+    pub upstream_priority: Option<String>,
+    pub upstream_sec_fetch_site: Option<String>,
+    pub upstream_sec_fetch_mode: Option<String>,
+    pub upstream_timeout: Option<Duration>,
+    pub max_upstream_timeout_ms: u64,
+    pub max_completion: Option<Duration>,
+    pub request_deadline: Option<Duration>,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_queue_depth: usize,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<u32>,
+    pub connection_rate_window: Duration,
+    pub circuit_breaker_threshold: Option<u32>,
+    pub circuit_breaker_window: Duration,
+    pub circuit_breaker_cooldown: Duration,
+    pub fallback_enabled: bool,
+    pub fallback_message: String,
+    /// The accepted Authorization header values, any request is served when empty.
+    pub authorizations: Vec<String>,
+    pub azure_compat: bool,
+    pub prompt_filter_results: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub chunked_response: bool,
+    pub default_stream: bool,
+    pub strict_accept: bool,
+    pub stream_error_events: bool,
+    pub early_role_frame: bool,
+    pub strict_schema: bool,
+    pub single_flight: bool,
+    pub debug_header: bool,
+    pub usage_webhook: Option<String>,
+    pub prompt_hook: Option<String>,
+    pub prompt_hook_timeout: Duration,
+    pub strip_markdown: bool,
+    pub max_response_chars: Option<usize>,
+    pub response_prefix: Option<String>,
+    pub response_suffix: Option<String>,
+    pub coalesce_chars: Option<usize>,
+    pub coalesce_interval: Option<Duration>,
+    pub min_frame_interval: Option<Duration>,
+    pub history_disabled: bool,
+    pub max_messages: usize,
+    pub max_batch_size: usize,
+    pub blocked_words: Vec<String>,
+    pub oai_device_ids: Vec<String>,
+    pub models_created: i64,
+    pub model_context_window: u64,
+    pub model_max_output_tokens: u64,
+    pub enable_playground: bool,
+    pub message_template: Option<String>,
+    pub system_prompt: Option<String>,
+    pub message_separator: String,
+    pub system_fingerprint: Option<String>,
+    pub completion_id_prefix: String,
+}
+
+impl Config {
+    /// Read the configuration from environment variables, reporting every problem at once.
+    pub fn from_env() -> Result<Self> {
+        Self::read(EnvReader::default())
+    }
+
+    /// Read the configuration from the given variables instead of the environment.
+    #[cfg(test)]
+    pub fn from_vars(vars: &[(&str, &str)]) -> Result<Self> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Self::read(EnvReader {
+            vars: Some(vars),
+            ..Default::default()
+        })
+    }
+
+    fn read(mut reader: EnvReader) -> Result<Self> {
+        let config = Self {
+            host: reader
+                .parse("HOST")
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: reader.parse("PORT").unwrap_or(PORT),
+            base_path: reader
+                .string("BASE_PATH")
+                .map(|v| v.trim_end_matches('/').to_string())
+                .unwrap_or_default(),
+            header_read_timeout: Duration::from_secs(
+                reader
+                    .parse("HEADER_READ_TIMEOUT_SECS")
+                    .unwrap_or(HEADER_READ_TIMEOUT_SECS),
+            ),
+            body_read_timeout: Duration::from_secs(
+                reader
+                    .parse("BODY_READ_TIMEOUT_SECS")
+                    .unwrap_or(BODY_READ_TIMEOUT_SECS),
+            ),
+            shutdown_drain: reader
+                .parse("SHUTDOWN_DRAIN_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or_default(),
+            max_body_size: reader.parse("MAX_BODY_SIZE").unwrap_or(MAX_BODY_SIZE),
+            log_file: reader.string("LOG_FILE"),
+            log_stdout: reader.bool("LOG_STDOUT").unwrap_or(true),
+            log_max_size: reader.parse("LOG_MAX_SIZE"),
+            log_max_files: reader.parse("LOG_MAX_FILES").unwrap_or(LOG_MAX_FILES),
+            upstream_base_url: reader
+                .string("UPSTREAM_BASE_URL")
+                .map(|v| v.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| UPSTREAM_BASE_URL.into()),
+            proxy: reader.string("ALL_PROXY"),
+            connect_timeout: Duration::from_secs(
+                reader
+                    .parse("CONNECT_TIMEOUT_SECS")
+                    .unwrap_or(CONNECT_TIMEOUT_SECS),
+            ),
+            upstream_ip_family: reader.parse("UPSTREAM_IP_FAMILY"),
+            upstream_min_tls: reader.parse("UPSTREAM_MIN_TLS"),
+            pool_max_idle_per_host: reader.parse("POOL_MAX_IDLE_PER_HOST"),
+            pool_idle_timeout: reader
+                .parse("POOL_IDLE_TIMEOUT_SECS")
+                .map(Duration::from_secs),
+            upstream_http2: reader.bool("UPSTREAM_HTTP2").unwrap_or_default(),
+            disable_pow: reader.bool("DISABLE_POW").unwrap_or_default(),
+            pow_threads: reader.parse("POW_THREADS").unwrap_or(1),
+            pow_format: ProofFormat {
+                algorithm: reader.parse("POW_ALGORITHM").unwrap_or_default(),
+                payload_template: reader.string("POW_PAYLOAD_TEMPLATE"),
+            },
+            selftest: reader.bool("SELFTEST").unwrap_or_default(),
+            auto_resume: reader.bool("AUTO_RESUME").unwrap_or_default(),
+            max_frame_size: reader.parse("MAX_FRAME_SIZE").unwrap_or(MAX_FRAME_SIZE),
+            max_upstream_bytes: reader.parse("MAX_UPSTREAM_BYTES"),
+            upstream_priority: reader.header_value("UPSTREAM_PRIORITY"),
+            upstream_sec_fetch_site: reader.header_value("UPSTREAM_SEC_FETCH_SITE"),
+            upstream_sec_fetch_mode: reader.header_value("UPSTREAM_SEC_FETCH_MODE"),
+            upstream_timeout: reader
+                .parse("UPSTREAM_TIMEOUT_MS")
+                .map(Duration::from_millis),
+            max_upstream_timeout_ms: reader
+                .parse("MAX_UPSTREAM_TIMEOUT_MS")
+                .unwrap_or(MAX_UPSTREAM_TIMEOUT_MS),
+            max_completion: reader.parse("MAX_COMPLETION_SECS").map(Duration::from_secs),
+            request_deadline: reader.parse("REQUEST_DEADLINE").map(Duration::from_secs),
+            max_concurrent_requests: reader.parse("MAX_CONCURRENT_REQUESTS"),
+            max_queue_depth: reader.parse("MAX_QUEUE_DEPTH").unwrap_or(MAX_QUEUE_DEPTH),
+            max_connections: reader.parse("MAX_CONNECTIONS"),
+            max_connections_per_ip: reader.parse("MAX_CONNECTIONS_PER_IP"),
+            connection_rate_window: Duration::from_secs(
+                reader
+                    .parse("CONNECTION_RATE_WINDOW_SECS")
+                    .unwrap_or(CONNECTION_RATE_WINDOW_SECS),
+            ),
+            circuit_breaker_threshold: reader.parse("CIRCUIT_BREAKER_THRESHOLD"),
+            circuit_breaker_window: Duration::from_secs(
+                reader
+                    .parse("CIRCUIT_BREAKER_WINDOW_SECS")
+                    .unwrap_or(CIRCUIT_BREAKER_WINDOW_SECS),
+            ),
+            circuit_breaker_cooldown: Duration::from_secs(
+                reader
+                    .parse("CIRCUIT_BREAKER_COOLDOWN_SECS")
+                    .unwrap_or(CIRCUIT_BREAKER_COOLDOWN_SECS),
+            ),
+            fallback_enabled: reader.bool("FALLBACK_ENABLED").unwrap_or_default(),
+            fallback_message: reader
+                .string("FALLBACK_MESSAGE")
+                .map(|v| unescape_newlines(&v))
+                .unwrap_or_else(|| FALLBACK_MESSAGE.into()),
+            authorizations: reader.authorizations(),
+            azure_compat: reader.bool("AZURE_COMPAT").unwrap_or_default(),
+            prompt_filter_results: reader.bool("PROMPT_FILTER_RESULTS").unwrap_or_default(),
+            trusted_proxies: reader.trusted_proxies(),
+            chunked_response: reader.bool("CHUNKED_RESPONSE").unwrap_or_default(),
+            default_stream: reader.bool("DEFAULT_STREAM").unwrap_or_default(),
+            strict_accept: reader.bool("STRICT_ACCEPT").unwrap_or_default(),
+            stream_error_events: reader.bool("STREAM_ERROR_EVENTS").unwrap_or_default(),
+            early_role_frame: reader.bool("EARLY_ROLE_FRAME").unwrap_or_default(),
+            strict_schema: reader.bool("STRICT_SCHEMA").unwrap_or_default(),
+            single_flight: reader.bool("SINGLE_FLIGHT").unwrap_or_default(),
+            debug_header: reader.bool("DEBUG_HEADER").unwrap_or_default(),
+            usage_webhook: reader.string("USAGE_WEBHOOK"),
+            prompt_hook: reader.string("PROMPT_HOOK"),
+            prompt_hook_timeout: Duration::from_secs(
+                reader
+                    .parse("PROMPT_HOOK_TIMEOUT_SECS")
+                    .unwrap_or(PROMPT_HOOK_TIMEOUT_SECS),
+            ),
+            strip_markdown: reader.bool("STRIP_MARKDOWN").unwrap_or_default(),
+            max_response_chars: reader.parse("MAX_RESPONSE_CHARS"),
+            response_prefix: reader
+                .string("RESPONSE_PREFIX")
+                .map(|v| unescape_newlines(&v)),
+            response_suffix: reader
+                .string("RESPONSE_SUFFIX")
+                .map(|v| unescape_newlines(&v)),
+            coalesce_chars: reader.parse("COALESCE_CHARS"),
+            coalesce_interval: reader
+                .parse("COALESCE_INTERVAL_MS")
+                .map(Duration::from_millis),
+            min_frame_interval: reader
+                .parse("MIN_FRAME_INTERVAL_MS")
+                .map(Duration::from_millis),
+            history_disabled: reader.bool("HISTORY_DISABLED").unwrap_or(true),
+            max_messages: reader.parse("MAX_MESSAGES").unwrap_or(MAX_MESSAGES),
+            max_batch_size: reader.parse("MAX_BATCH_SIZE").unwrap_or(MAX_BATCH_SIZE),
+            blocked_words: reader.blocked_words(),
+            oai_device_ids: reader
+                .string("OAI_DEVICE_IDS")
+                .map(|v| {
+                    v.split(',')
+                        .map(|v| v.trim().to_string())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            models_created: reader
+                .parse("MODELS_CREATED")
+                .unwrap_or_else(|| Utc::now().timestamp()),
+            model_context_window: reader
+                .parse("MODEL_CONTEXT_WINDOW")
+                .unwrap_or(MODEL_CONTEXT_WINDOW),
+            model_max_output_tokens: reader
+                .parse("MODEL_MAX_OUTPUT_TOKENS")
+                .unwrap_or(MODEL_MAX_OUTPUT_TOKENS),
+            enable_playground: reader.bool("ENABLE_PLAYGROUND").unwrap_or_default(),
+            message_template: reader
+                .string("MESSAGE_TEMPLATE")
+                .map(|v| unescape_newlines(&v)),
+            system_prompt: reader
+                .string("SYSTEM_PROMPT")
+                .map(|v| unescape_newlines(&v)),
+            message_separator: reader
+                .var("MESSAGE_SEPARATOR")
+                .map(|v| unescape_newlines(&v))
+                .unwrap_or_else(|| "\n".into()),
+            system_fingerprint: reader.string("SYSTEM_FINGERPRINT"),
+            completion_id_prefix: reader
+                .string("COMPLETION_ID_PREFIX")
+                .unwrap_or_else(|| COMPLETION_ID_PREFIX.into()),
+        };
+        let mut errors = reader.errors;
+        errors.extend(config.validate());
+        if !errors.is_empty() {
+            let errors: Vec<String> = errors.iter().map(|v| format!("  - {v}")).collect();
+            bail!("Invalid configuration:\n{}", errors.join("\n"));
+        }
+        Ok(config)
+    }
+
+    /// Check the combinations of settings that cannot be validated one variable at a time.
+    fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if let Some(proxy) = &self.proxy {
+            let scheme = proxy.split_once("://").map(|(v, _)| v.to_lowercase());
+            if !scheme.is_some_and(|v| PROXY_SCHEMES.contains(&v.as_str())) {
+                errors.push(format!(
+                    "$ALL_PROXY: unsupported proxy '{proxy}', expected one of {}",
+                    PROXY_SCHEMES.map(|v| format!("{v}://")).join(", ")
+                ));
+            }
+        }
+        if !self.base_path.is_empty() && !self.base_path.starts_with('/') {
+            errors.push(format!(
+                "$BASE_PATH: must start with '/', e.g. '/{}'",
+                self.base_path
+            ));
+        }
+        if !["http://", "https://"]
+            .iter()
+            .any(|v| self.upstream_base_url.starts_with(v))
+        {
+            errors.push(format!(
+                "$UPSTREAM_BASE_URL: unsupported url '{}', expected http:// or https://",
+                self.upstream_base_url
+            ));
+        }
+        if self.connect_timeout.is_zero() {
+            errors.push("$CONNECT_TIMEOUT_SECS: must be greater than 0".into());
+        }
+        if self.request_deadline.is_some_and(|v| v.is_zero()) {
+            errors.push("$REQUEST_DEADLINE: must be greater than 0".into());
+        }
+        if let Some(url) = &self.usage_webhook {
+            if !["http://", "https://"].iter().any(|v| url.starts_with(v)) {
+                errors.push(format!(
+                    "$USAGE_WEBHOOK: unsupported url '{url}', expected http:// or https://"
+                ));
+            }
+        }
+        if self.port == 0 {
+            errors.push("$PORT: must not be 0".into());
+        }
+        if self.header_read_timeout.is_zero() {
+            errors.push("$HEADER_READ_TIMEOUT_SECS: must be greater than 0".into());
+        }
+        if self.body_read_timeout.is_zero() {
+            errors.push("$BODY_READ_TIMEOUT_SECS: must be greater than 0".into());
+        }
+        if !self.log_stdout && self.log_file.is_none() {
+            errors.push("$LOG_STDOUT: must not be false unless $LOG_FILE is set".into());
+        }
+        if self.log_max_size == Some(0) {
+            errors.push("$LOG_MAX_SIZE: must be greater than 0".into());
+        }
+        if self.max_frame_size == 0 {
+            errors.push("$MAX_FRAME_SIZE: must be greater than 0".into());
+        }
+        if self.max_upstream_bytes == Some(0) {
+            errors.push("$MAX_UPSTREAM_BYTES: must be greater than 0".into());
+        }
+        if let Some(v) = self
+            .oai_device_ids
+            .iter()
+            .find(|v| HeaderValue::from_str(v).is_err())
+        {
+            errors.push(format!("$OAI_DEVICE_IDS: invalid device id '{v}'"));
+        }
+        if self.pow_threads == 0 {
+            errors.push("$POW_THREADS: must be greater than 0".into());
+        }
+        if let Some(template) = &self.pow_format.payload_template {
+            if !template.contains("{nonce}") {
+                errors.push("$POW_PAYLOAD_TEMPLATE: must contain the {nonce} placeholder".into());
+            }
+        }
+        if self.max_response_chars == Some(0) {
+            errors.push("$MAX_RESPONSE_CHARS: must be greater than 0".into());
+        }
+        if self.max_connections == Some(0) {
+            errors.push("$MAX_CONNECTIONS: must be greater than 0".into());
+        }
+        if self.max_connections_per_ip == Some(0) {
+            errors.push("$MAX_CONNECTIONS_PER_IP: must be greater than 0".into());
+        }
+        if self.connection_rate_window.is_zero() {
+            errors.push("$CONNECTION_RATE_WINDOW_SECS: must be greater than 0".into());
+        }
+        if self.prompt_hook_timeout.is_zero() {
+            errors.push("$PROMPT_HOOK_TIMEOUT_SECS: must be greater than 0".into());
+        }
+        if self.max_concurrent_requests == Some(0) {
+            errors.push("$MAX_CONCURRENT_REQUESTS: must be greater than 0".into());
+        }
+        if self.circuit_breaker_threshold == Some(0) {
+            errors.push("$CIRCUIT_BREAKER_THRESHOLD: must be greater than 0".into());
+        }
+        if self.fallback_enabled && self.circuit_breaker_threshold.is_none() {
+            errors.push(
+                "$FALLBACK_ENABLED: must not be true unless $CIRCUIT_BREAKER_THRESHOLD is set"
+                    .into(),
+            );
+        }
+        if self.coalesce_chars == Some(0) {
+            errors.push("$COALESCE_CHARS: must be greater than 0".into());
+        }
+        if self.max_messages == 0 {
+            errors.push("$MAX_MESSAGES: must be greater than 0".into());
+        }
+        if self.max_batch_size == 0 {
+            errors.push("$MAX_BATCH_SIZE: must be greater than 0".into());
+        }
+        if let Some(upstream_timeout) = self.upstream_timeout {
+            if upstream_timeout.as_millis() > self.max_upstream_timeout_ms as u128 {
+                errors.push(format!(
+                    "$UPSTREAM_TIMEOUT_MS: must not exceed $MAX_UPSTREAM_TIMEOUT_MS ({})",
+                    self.max_upstream_timeout_ms
+                ));
+            }
+        }
+        if self.completion_id_prefix.len() > MAX_COMPLETION_ID_PREFIX_LEN
+            || !self
+                .completion_id_prefix
+                .chars()
+                .all(|v| v.is_ascii_alphanumeric() || "-_.".contains(v))
+        {
+            errors.push(format!(
+                "$COMPLETION_ID_PREFIX: must be at most {MAX_COMPLETION_ID_PREFIX_LEN} letters, digits, '-', '_' or '.'"
+            ));
+        }
+        if let Some(template) = &self.message_template {
+            if !template.contains("{content}") {
+                errors.push("$MESSAGE_TEMPLATE: must contain '{content}'".into());
+            }
+        }
+        errors
+    }
+}
+
+/// Describe the supported environment variables for the startup banner.
+pub fn env_vars_help(addr: SocketAddr) -> Vec<(&'static str, String)> {
+    vec![
+        ("HOST", "change the listening address, defaulting to 0.0.0.0, use :: to accept both IPv6 and IPv4".into()),
+        ("PORT", format!("change the listening port, defaulting to {PORT}")),
+        ("BASE_PATH", "serve the routes under the given path prefix, e.g. '/api/chatgpt' for a reverse proxy".into()),
+        ("HEADER_READ_TIMEOUT_SECS", format!("drop connections that do not finish sending request headers in time, defaulting to {HEADER_READ_TIMEOUT_SECS}")),
+        ("BODY_READ_TIMEOUT_SECS", format!("reject requests whose body is not received in time, defaulting to {BODY_READ_TIMEOUT_SECS}")),
+        ("MAX_BODY_SIZE", format!("reject request bodies larger than the given bytes once decompressed, defaulting to {MAX_BODY_SIZE}")),
+        ("SHUTDOWN_DRAIN_SECS", format!("keep serving for the given seconds after CTRL+C while http://{addr}/ready reports not ready")),
+        ("LOG_FILE", "also write the logs to the given file".into()),
+        ("LOG_STDOUT", "write the logs to the console, defaulting to true, set to false to only write $LOG_FILE".into()),
+        ("LOG_MAX_SIZE", "rotate $LOG_FILE once it exceeds the given bytes".into()),
+        ("LOG_MAX_FILES", format!("keep the given number of rotated log files, defaulting to {LOG_MAX_FILES}")),
+        ("UPSTREAM_BASE_URL", format!("send the upstream requests to another server, e.g. a mock for testing, defaulting to {UPSTREAM_BASE_URL}")),
+        ("ALL_PROXY", "configure the proxy server, supporting HTTP, HTTPS, and SOCKS5 protocols".into()),
+        ("CONNECT_TIMEOUT_SECS", format!("give up connecting to the upstream or the proxy after the given seconds, defaulting to {CONNECT_TIMEOUT_SECS}")),
+        ("UPSTREAM_IP_FAMILY", "only connect to the upstream over 'ipv4' or 'ipv6'".into()),
+        ("UPSTREAM_MIN_TLS", "refuse upstream connections below TLS '1.2' or '1.3', defaulting to any version rustls supports".into()),
+        ("POOL_MAX_IDLE_PER_HOST", "limit the idle upstream connections kept per host, defaulting to unlimited".into()),
+        ("POOL_IDLE_TIMEOUT_SECS", "close idle upstream connections after the given seconds, defaulting to 90".into()),
+        ("UPSTREAM_HTTP2", "force HTTP/2 for upstream connections".into()),
+        ("OAI_DEVICE_IDS", "rotate the requests through the given comma-separated device ids instead of a random one each".into()),
+        ("DISABLE_POW", "skip the proof of work unless the upstream rejects the conversation without it".into()),
+        ("POW_THREADS", "search the proof of work on the given number of threads, defaulting to 1".into()),
+        ("POW_ALGORITHM", "hash the proof of work with 'sha3-512', 'sha3-256', 'sha512' or 'sha256', defaulting to sha3-512".into()),
+        ("POW_PAYLOAD_TEMPLATE", "replace the hashed browser fingerprint, e.g. '[{screen},\"{datetime}\",{heap_size},{nonce},\"{user_agent}\"]'".into()),
+        ("SELFTEST", "solve a known proof of work at startup and warn if it fails".into()),
+        ("AUTO_RESUME", "re-issue the conversation once when the upstream connection breaks mid-stream, skipping the content already sent".into()),
+        ("MAX_FRAME_SIZE", format!("fail the completion when a single upstream event exceeds the given bytes, defaulting to {MAX_FRAME_SIZE}")),
+        ("MAX_UPSTREAM_BYTES", "stop reading a completion from the upstream after the given bytes and return the content so far with finish_reason 'length'".into()),
+        ("UPSTREAM_PRIORITY", "override the `priority` header sent upstream, defaulting to 'u=1, i'".into()),
+        ("UPSTREAM_SEC_FETCH_SITE", "override the `sec-fetch-site` header sent upstream, defaulting to 'same-origin'".into()),
+        ("UPSTREAM_SEC_FETCH_MODE", "override the `sec-fetch-mode` header sent upstream, defaulting to 'cors'".into()),
+        ("UPSTREAM_TIMEOUT_MS", "time out upstream requests and idle streams, overridable per request by the X-Upstream-Timeout-Ms header".into()),
+        ("MAX_UPSTREAM_TIMEOUT_MS", format!("cap the X-Upstream-Timeout-Ms header, defaulting to {MAX_UPSTREAM_TIMEOUT_MS}")),
+        ("MAX_COMPLETION_SECS", "stop generating after the given seconds and return the content so far with finish_reason 'length'".into()),
+        ("REQUEST_DEADLINE", "answer completions within the given seconds of their arrival, including the proof of work, with the content so far and finish_reason 'length'".into()),
+        ("MAX_CONCURRENT_REQUESTS", "limit the completions in progress, queueing the others in arrival order".into()),
+        ("MAX_QUEUE_DEPTH", format!("reject completions with 503 when the given number are already queued, defaulting to {MAX_QUEUE_DEPTH}")),
+        ("MAX_CONNECTIONS", "keep at most the given number of connections open, deferring new ones until others close".into()),
+        ("MAX_CONNECTIONS_PER_IP", "close new connections from an address that opened the given number within the window".into()),
+        ("CONNECTION_RATE_WINDOW_SECS", format!("count the connections per address within the given seconds, defaulting to {CONNECTION_RATE_WINDOW_SECS}")),
+        ("CIRCUIT_BREAKER_THRESHOLD", "fail fast once the given number of upstream failures happen within the window".into()),
+        ("CIRCUIT_BREAKER_WINDOW_SECS", format!("count the upstream failures within the given seconds, defaulting to {CIRCUIT_BREAKER_WINDOW_SECS}")),
+        ("CIRCUIT_BREAKER_COOLDOWN_SECS", format!("fail fast for the given seconds before probing the upstream again, defaulting to {CIRCUIT_BREAKER_COOLDOWN_SECS}")),
+        ("FALLBACK_ENABLED", "answer with $FALLBACK_MESSAGE and the header X-Fallback: 1 instead of failing while the circuit breaker is open".into()),
+        ("FALLBACK_MESSAGE", format!("the canned answer served by $FALLBACK_ENABLED, defaulting to '{FALLBACK_MESSAGE}'")),
+        ("AUTHORIZATION", "only for internal use to protect the API and will not be sent to OpenAI".into()),
+        ("AUTHORIZATION_FILE", "read the accepted Authorization values one per line from the given file instead of $AUTHORIZATION, e.g. a mounted secret".into()),
+        ("AZURE_COMPAT", "also serve /openai/deployments/{deployment}/chat/completions, accepting the key as an `api-key` header".into()),
+        ("PROMPT_FILTER_RESULTS", "always include Azure style `prompt_filter_results`, otherwise completions only include them when the upstream sends moderation and streams never do".into()),
+        ("TRUSTED_PROXIES", "read the client address from X-Forwarded-For or X-Real-IP when the peer is in the given comma-separated CIDRs".into()),
+        ("CHUNKED_RESPONSE", "send non-streaming responses with chunked transfer encoding".into()),
+        ("DEFAULT_STREAM", "stream the responses of requests that omit `stream`".into()),
+        ("USAGE_WEBHOOK", "POST the usage, latency and outcome of every completion to the given url".into()),
+        ("PROMPT_HOOK", "pipe {\"system_prompt\", \"prompt\"} as JSON through the given shell command, which prints the rewritten object".into()),
+        ("PROMPT_HOOK_TIMEOUT_SECS", format!("send the original prompt when $PROMPT_HOOK takes longer than the given seconds, defaulting to {PROMPT_HOOK_TIMEOUT_SECS}")),
+        ("STRICT_SCHEMA", "reject requests that do not follow the OpenAI chat completion schema, naming each invalid field".into()),
+        ("SINGLE_FLIGHT", "let identical concurrent requests share one upstream completion".into()),
+        ("DEBUG_HEADER", "honor `X-Debug: 1`, adding the upstream status, proof of work and timings to failed responses and `x_timing` to streamed chunks".into()),
+        ("STRICT_ACCEPT", "respond without streaming when the Accept header rejects text/event-stream despite `stream: true`".into()),
+        ("STREAM_ERROR_EVENTS", "end streams interrupted by the upstream with an error event instead of finish reason 'length'".into()),
+        ("EARLY_ROLE_FRAME", "send the role delta of streams right away instead of after the proof of work, reporting later failures as error events".into()),
+        ("STRIP_MARKDOWN", "convert responses to plain text, overridable per request by the X-Strip-Markdown header".into()),
+        ("MAX_RESPONSE_CHARS", "cut responses at the given number of characters with finish_reason 'length'".into()),
+        ("RESPONSE_PREFIX", "prepend the given text to every response".into()),
+        ("RESPONSE_SUFFIX", "append the given text to every response".into()),
+        ("COALESCE_CHARS", "batch streamed deltas until they reach the given number of characters".into()),
+        ("COALESCE_INTERVAL_MS", "batch streamed deltas for up to the given milliseconds".into()),
+        ("MIN_FRAME_INTERVAL_MS", "pace streamed deltas to at most one frame per given milliseconds".into()),
+        ("HISTORY_DISABLED", "disable chat history and training, defaulting to true, overridable per request by the X-History-Disabled header".into()),
+        ("MAX_MESSAGES", format!("limit the number of messages per request, defaulting to {MAX_MESSAGES}")),
+        ("MAX_BATCH_SIZE", format!("limit the number of requests per call to /v1/chat/completions/batch, defaulting to {MAX_BATCH_SIZE}")),
+        ("BLOCKED_WORDS", "refuse prompts containing any of the comma-separated words, case-insensitively".into()),
+        ("BLOCKED_WORDS_FILE", "refuse prompts containing any of the words listed one per line in the given file".into()),
+        ("MODELS_CREATED", "set the unix timestamp reported as `created` in the models list, defaulting to the server start time".into()),
+        ("MODEL_CONTEXT_WINDOW", format!("set the `context_window` reported in the models list, defaulting to {MODEL_CONTEXT_WINDOW}")),
+        ("MODEL_MAX_OUTPUT_TOKENS", format!("set the `max_output_tokens` reported in the models list, defaulting to {MODEL_MAX_OUTPUT_TOKENS}")),
+        ("ENABLE_PLAYGROUND", format!("serve a minimal chat page at http://{addr}/ for manual testing")),
+        ("MESSAGE_TEMPLATE", "label each flattened message, e.g. '{role}: {content}', defaulting to the raw content".into()),
+        ("MESSAGE_SEPARATOR", "join the flattened messages, defaulting to '\\n'".into()),
+        ("SYSTEM_PROMPT", "prepend the given system prompt to every request, before the X-System-Prompt header and the system message".into()),
+        ("SYSTEM_FINGERPRINT", "include the given `system_fingerprint` in completion responses".into()),
+        ("COMPLETION_ID_PREFIX", format!("start the completion ids with the given prefix instead of {COMPLETION_ID_PREFIX}")),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl FromStr for IpFamily {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "ipv4" | "4" => Ok(Self::V4),
+            "ipv6" | "6" => Ok(Self::V6),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V4 => write!(f, "IPv4"),
+            Self::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    V1_2,
+    V1_3,
+}
+
+impl FromStr for TlsVersion {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.to_lowercase().trim_start_matches("tls").trim() {
+            "1.2" => Ok(Self::V1_2),
+            "1.3" => Ok(Self::V1_3),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn unescape_newlines(value: &str) -> String {
+    value.replace("\\n", "\n")
+}
+
+#[derive(Debug, Default)]
+struct EnvReader {
+    errors: Vec<String>,
+    /// Read instead of the environment when set.
+    vars: Option<HashMap<String, String>>,
+}
+
+impl EnvReader {
+    fn var(&self, name: &str) -> Option<String> {
+        match &self.vars {
+            Some(vars) => vars.get(name).cloned(),
+            None => env::var(name).ok(),
+        }
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        self.var(name).filter(|v| !v.is_empty())
+    }
+
+    fn blocked_words(&mut self) -> Vec<String> {
+        let mut words: Vec<String> = vec![];
+        if let Some(value) = self.string("BLOCKED_WORDS") {
+            words.extend(value.split(',').map(|v| v.to_string()));
+        }
+        if let Some(path) = self.string("BLOCKED_WORDS_FILE") {
+            match fs::read_to_string(&path) {
+                Ok(value) => words.extend(value.lines().map(|v| v.to_string())),
+                Err(err) => self.errors.push(format!(
+                    "$BLOCKED_WORDS_FILE: failed to read '{path}', {err}"
+                )),
+            }
+        }
+        words
+            .into_iter()
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    fn authorizations(&mut self) -> Vec<String> {
+        let Some(path) = self.string("AUTHORIZATION_FILE") else {
+            return self.string("AUTHORIZATION").into_iter().collect();
+        };
+        match fs::read_to_string(&path) {
+            Ok(value) => {
+                let values: Vec<String> = value
+                    .lines()
+                    .map(|v| v.trim().to_string())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                // An empty secret must not leave the API open.
+                if values.is_empty() {
+                    self.errors
+                        .push(format!("$AUTHORIZATION_FILE: no values in '{path}'"));
+                }
+                values
+            }
+            Err(err) => {
+                self.errors.push(format!(
+                    "$AUTHORIZATION_FILE: failed to read '{path}', {err}"
+                ));
+                vec![]
+            }
+        }
+    }
+
+    fn trusted_proxies(&mut self) -> Vec<IpNet> {
+        let Some(value) = self.string("TRUSTED_PROXIES") else {
+            return vec![];
+        };
+        let mut proxies = vec![];
+        for v in value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+            match v
+                .parse::<IpNet>()
+                .or_else(|_| v.parse::<IpAddr>().map(IpNet::from))
+            {
+                Ok(v) => proxies.push(v),
+                Err(_) => self
+                    .errors
+                    .push(format!("$TRUSTED_PROXIES: invalid CIDR '{v}'")),
+            }
+        }
+        proxies
+    }
+
+    fn header_value(&mut self, name: &str) -> Option<String> {
+        let value = self.string(name)?;
+        if HeaderValue::from_str(&value).is_err() {
+            self.errors
+                .push(format!("${name}: '{value}' is not a valid header value"));
+            return None;
+        }
+        Some(value)
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = self.string(name)?;
+        match value.parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.errors
+                    .push(format!("${name}: invalid value '{value}'"));
+                None
+            }
+        }
+    }
+
+    fn bool(&mut self, name: &str) -> Option<bool> {
+        let value = self.string(name)?;
+        match parse_bool(&value) {
+            Some(v) => Some(v),
+            None => {
+                self.errors.push(format!(
+                    "${name}: invalid value '{value}', expected true or false"
+                ));
+                None
+            }
+        }
+    }
+}
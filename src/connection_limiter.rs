@@ -0,0 +1,60 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Entries are pruned once there are this many addresses, bounding the memory use.
+const PRUNE_THRESHOLD: usize = 10000;
+
+/// Refuse new connections from addresses that open too many within a window, so a client
+/// reconnecting in a tight loop is dropped before any request is read.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    max_connections: u32,
+    window: Duration,
+    state: Mutex<HashMap<IpAddr, Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    connections: u32,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: u32, window: Duration) -> Self {
+        Self {
+            max_connections,
+            window,
+            state: Default::default(),
+        }
+    }
+
+    /// Count a new connection from the address and check whether it may be served.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state.len() >= PRUNE_THRESHOLD {
+            state.retain(|_, v| now.duration_since(v.start) <= self.window);
+        }
+        let window = state.entry(ip).or_insert(Window {
+            start: now,
+            connections: 0,
+        });
+        if now.duration_since(window.start) > self.window {
+            window.start = now;
+            window.connections = 0;
+        }
+        window.connections = window.connections.saturating_add(1);
+        if window.connections == self.max_connections + 1 {
+            warn!(
+                "Refusing connections from {ip} for up to {}s, it opened more than {} connections",
+                self.window.as_secs(),
+                self.max_connections
+            );
+        }
+        window.connections <= self.max_connections
+    }
+}
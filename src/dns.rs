@@ -0,0 +1,38 @@
+use crate::config::IpFamily;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::SocketAddr;
+
+/// Resolve the upstream with the system resolver, keeping only the addresses of one family.
+#[derive(Debug)]
+pub struct FamilyResolver {
+    family: IpFamily,
+}
+
+impl FamilyResolver {
+    pub fn new(family: IpFamily) -> Self {
+        Self { family }
+    }
+}
+
+impl Resolve for FamilyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let family = self.family;
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            // The port is replaced by the connector.
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|v| match family {
+                    IpFamily::V4 => v.is_ipv4(),
+                    IpFamily::V6 => v.is_ipv6(),
+                })
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("No {family} address found for '{host}'").into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
@@ -0,0 +1,161 @@
+use bytes::Bytes;
+use eventsource_stream::{Event as MessageEvent, EventStreamError, Eventsource};
+use futures_util::{future::BoxFuture, stream::BoxStream, Stream, StreamExt};
+use reqwest::{
+    header::{HeaderValue, ACCEPT, CONTENT_TYPE},
+    RequestBuilder, Response, StatusCode,
+};
+use std::fmt;
+
+/// The events of the upstream conversation. Unlike `reqwest_eventsource::EventSource`, the body
+/// is read with a bounded buffer, an event larger than the limit fails the stream before it is
+/// buffered in full. It never reconnects by itself.
+pub struct EventSource {
+    state: State,
+    max_frame_size: usize,
+}
+
+enum State {
+    Connecting(BoxFuture<'static, reqwest::Result<Response>>),
+    Open(BoxStream<'static, Result<MessageEvent, EventStreamError<Error>>>),
+    Closed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Open,
+    Message(MessageEvent),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(reqwest::Error),
+    InvalidStatusCode(StatusCode, Box<Response>),
+    InvalidContentType(HeaderValue, Box<Response>),
+    /// A single event exceeded the given bytes.
+    FrameTooLarge(usize),
+    Parser(String),
+    StreamEnded,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(err) => write!(f, "{err}"),
+            Self::InvalidStatusCode(status, _) => write!(f, "Invalid status code: {status}"),
+            Self::InvalidContentType(value, _) => write!(f, "Invalid header value: {value:?}"),
+            Self::FrameTooLarge(max) => {
+                write!(
+                    f,
+                    "Upstream sent a frame exceeding the limit of {max} bytes"
+                )
+            }
+            Self::Parser(err) => write!(f, "Invalid upstream event stream, {err}"),
+            Self::StreamEnded => write!(f, "Stream ended"),
+        }
+    }
+}
+
+impl From<EventStreamError<Error>> for Error {
+    fn from(err: EventStreamError<Error>) -> Self {
+        match err {
+            EventStreamError::Utf8(err) => Self::Parser(err.to_string()),
+            EventStreamError::Parser(err) => Self::Parser(err.to_string()),
+            EventStreamError::Transport(err) => err,
+        }
+    }
+}
+
+impl EventSource {
+    pub fn new(builder: RequestBuilder, max_frame_size: usize) -> Self {
+        let res = builder
+            .header(ACCEPT, HeaderValue::from_static("text/event-stream"))
+            .send();
+        Self {
+            state: State::Connecting(Box::pin(res)),
+            max_frame_size,
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+    }
+
+    /// The next event, `Error::StreamEnded` once the body ends and then `None`. Cancel safe.
+    pub async fn next(&mut self) -> Option<Result<Event, Error>> {
+        let (event, state) = match &mut self.state {
+            State::Connecting(res) => match res.await.map_err(Error::Transport) {
+                Ok(res) => match check_response(res) {
+                    Ok(res) => {
+                        let body = limit_frames(res.bytes_stream(), self.max_frame_size);
+                        (Ok(Event::Open), State::Open(body.eventsource().boxed()))
+                    }
+                    Err(err) => (Err(err), State::Closed),
+                },
+                Err(err) => (Err(err), State::Closed),
+            },
+            State::Open(events) => match events.next().await {
+                Some(Ok(event)) => return Some(Ok(Event::Message(event))),
+                Some(Err(err)) => (Err(err.into()), State::Closed),
+                None => (Err(Error::StreamEnded), State::Closed),
+            },
+            State::Closed => return None,
+        };
+        self.state = state;
+        Some(event)
+    }
+}
+
+fn check_response(res: Response) -> Result<Response, Error> {
+    let status = res.status();
+    if status != StatusCode::OK {
+        return Err(Error::InvalidStatusCode(status, Box::new(res)));
+    }
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_static(""));
+    let is_event_stream = content_type
+        .to_str()
+        .ok()
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().eq_ignore_ascii_case("text/event-stream"))
+        .unwrap_or_default();
+    if !is_event_stream {
+        return Err(Error::InvalidContentType(content_type, Box::new(res)));
+    }
+    Ok(res)
+}
+
+/// Fail the body as soon as the event being read grows past `max_size` bytes, not counting the
+/// line breaks. An empty line ends the event.
+fn limit_frames(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    max_size: usize,
+) -> impl Stream<Item = Result<Bytes, Error>> + Send + 'static {
+    let (mut size, mut line_size, mut prev) = (0, 0, 0);
+    body.map(move |chunk| {
+        let chunk = chunk.map_err(Error::Transport)?;
+        for &byte in chunk.iter() {
+            match byte {
+                b'\n' if prev == b'\r' => {}
+                b'\r' | b'\n' => {
+                    if line_size == 0 {
+                        size = 0;
+                    }
+                    line_size = 0;
+                }
+                _ => {
+                    line_size += 1;
+                    size += 1;
+                }
+            }
+            prev = byte;
+            if size > max_size {
+                return Err(Error::FrameTooLarge(max_size));
+            }
+        }
+        Ok(chunk)
+    })
+}
@@ -1,43 +1,110 @@
+mod circuit_breaker;
+mod config;
+mod connection_limiter;
+mod dns;
+mod event_source;
+mod log_file;
+mod markdown;
+#[cfg(test)]
+mod mock_upstream;
+mod prompt_hook;
+mod proof;
+mod schema;
+mod single_flight;
+#[cfg(test)]
+mod tests;
+mod transform;
+mod webhook;
+mod websocket;
+
 #[macro_use]
 extern crate log;
 
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{env_vars_help, parse_bool, Config, TlsVersion};
+use crate::connection_limiter::ConnectionLimiter;
+use crate::event_source::{Error as EventSourceError, Event, EventSource};
+use crate::log_file::{LogWriter, RotatingFile};
+use crate::prompt_hook::PromptHook;
+use crate::proof::ProofFormat;
+use crate::single_flight::{Join, SingleFlight};
+use crate::webhook::UsageWebhook;
+use crate::websocket::WebSocket;
+
 use anyhow::{anyhow, bail, Result};
 use base64::{engine::general_purpose::STANDARD, Engine};
 use bytes::Bytes;
 use chrono::Utc;
+use flate2::read::{GzDecoder, ZlibDecoder};
 use futures_util::StreamExt;
-use http::{HeaderMap, HeaderValue, Response, StatusCode};
-use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use http::{HeaderMap, HeaderValue, Response, StatusCode, Uri};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, LengthLimitError, Limited, StreamBody};
 use hyper::{
     body::{Frame, Incoming},
     service::service_fn,
 };
-use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use reqwest::{Client, ClientBuilder, Method, Proxy};
-use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
+use ring::digest::{digest, SHA256};
 use serde_json::{json, Value};
-use sha3::{Digest, Sha3_512};
-use std::{convert::Infallible, env, sync::Arc, time::Duration};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    env,
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     net::TcpListener,
     sync::{
-        mpsc::{self, Sender},
-        oneshot,
+        mpsc::{self, Receiver, Sender},
+        oneshot, OwnedSemaphorePermit, Semaphore,
     },
 };
 use tokio_graceful::Shutdown;
 use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 
-const PORT: u16 = 3040;
-const CONVERSATION_URL: &str = "https://chat.openai.com/backend-anon/conversation";
-const CHAT_REQUIREMENTS_URL: &str =
-    "https://chat.openai.com/backend-anon/sentinel/chat-requirements";
-const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MODELS: [&str; 1] = ["gpt-3.5-turbo"];
+const CONVERSATION_PATH: &str = "/backend-anon/conversation";
+const CHAT_REQUIREMENTS_PATH: &str = "/backend-anon/sentinel/chat-requirements";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const EMPTY_CONTENT_ERROR: &str = "upstream produced no content";
+const CLOUDFLARE_CHALLENGE_ERROR: &str =
+    "upstream blocked by Cloudflare challenge; try a different proxy";
+const CLOUDFLARE_CHALLENGE_MARKERS: [&str; 4] = [
+    "Just a moment...",
+    "challenge-platform",
+    "_cf_chl_opt",
+    "cf-browser-verification",
+];
+const POW_MAX_ITERATIONS: usize = 100000;
+const POW_CANCEL_CHECK_INTERVAL: usize = 1000;
+/// The browser's `performance.memory.jsHeapSizeLimit`, the third element of the proof payload.
+const POW_HEAP_SIZE_LIMIT: u64 = 4294705152;
+/// Prefixes a solved proof token.
+const POW_TOKEN_PREFIX: &str = "gAAAAAB";
+/// Prefixes the token sent when the proof of work could not be solved within the iterations.
+const POW_FALLBACK_TOKEN_PREFIX: &str = "gAAAAABwQ8Lk5FbGpA2NcR9dShT6gYjU7VxZ4D";
+/// A seed and difficulty whose proof is found within a few dozen iterations.
+const SELFTEST_SEED: &str = "0.8261427183718519";
+const SELFTEST_DIFFICULTY: &str = "0fffff";
+const CHUNK_SIZE: usize = 8192;
+/// The upstream is asked once more when its answer does not match a `json_schema` response format.
+const JSON_SCHEMA_ATTEMPTS: usize = 2;
+const PLAYGROUND_HTML: &str = include_str!("playground.html");
 const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/123.0.0.0 Safari/537.36";
 
 lazy_static::lazy_static! {
+    /// Stands in for the browser's screen size, the first element of the proof payload.
     static ref PROOF_V1: u32 = {
         let mut rng = rand::thread_rng();
         rng.gen_range(2000..8000)
@@ -46,95 +113,249 @@ lazy_static::lazy_static! {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_logger();
-
-    let mut has_envs = [false; 3];
-
-    let port = if let Ok(port) = env::var("PORT") {
-        has_envs[0] = true;
-        port.parse::<u16>()
-            .map_err(|_| anyhow!("Invalid environment variable $PORT"))?
-    } else {
-        PORT
-    };
-    let mut client_builder = ClientBuilder::new().connect_timeout(CONNECT_TIMEOUT);
-    if let Ok(proxy) = env::var("ALL_PROXY") {
-        has_envs[1] = true;
-        client_builder = client_builder.proxy(
-            Proxy::all(proxy)
-                .map_err(|err| anyhow!("Invalid environment variable $ALL_PROXY, {err}"))?,
-        );
-    };
-    let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{port}")).await?;
+    let config = Config::from_env()?;
+    init_logger(&config)?;
+    if config.selftest {
+        proof_self_test(&config.pow_format, config.pow_threads);
+    }
+    let addr = SocketAddr::new(config.host, config.port);
+    let listener = bind_listener(addr)?;
+    let server = Arc::new(Server::new(config)?);
+    let stop_server = server.clone().run(listener).await?;
 
-    let authorization = env::var("AUTHORIZATION").ok().and_then(|v| {
-        if v.is_empty() {
-            None
-        } else {
-            has_envs[2] = true;
-            Some(v)
-        }
-    });
-    let server = Arc::new(Server {
-        client: client_builder.build()?,
-        authorization,
-    });
-    let [port_has_env, all_proxy_has_env, authorization_has_env] =
-        has_envs.map(|v| if v { " ✅" } else { "" });
-    let stop_server = server.run(listener).await?;
+    let env_vars = env_vars_help(addr);
+    let env_vars: Vec<String> = env_vars
+        .iter()
+        .map(|(name, description)| {
+            let has_env = env::var(name).map(|v| !v.is_empty()).unwrap_or_default();
+            let has_env = if has_env { " ✅" } else { "" };
+            format!("  - {name}: {description}{has_env}")
+        })
+        .collect();
+    let env_vars = env_vars.join("\n");
+    let base_path = &server.config.base_path;
     println!(
-        r#"Access the API server at: http://0.0.0.0:{port}/v1/chat/completions
+        r#"ChatGPT Free API {VERSION}
+
+Access the API server at: http://{addr}{base_path}/v1/chat/completions
 
 Environment Variables:
-  - PORT: change the listening port, defaulting to {PORT}{port_has_env}
-  - ALL_PROXY: configure the proxy server, supporting HTTP, HTTPS, and SOCKS5 protocols{all_proxy_has_env}
-  - AUTHORIZATION: only for internal use to protect the API and will not be sent to OpenAI{authorization_has_env}
+{env_vars}
 
 Please contact us at https://github.com/xsigoking/chatgpt-free-api if you encounter any issues.
 "#
     );
 
     shutdown_signal().await;
+    server.shutting_down.store(true, Ordering::Relaxed);
+    let drain = server.config.shutdown_drain;
+    if !drain.is_zero() {
+        info!(
+            "Shutting down, serving for another {}s while traffic drains, press CTRL+C again to stop now",
+            drain.as_secs()
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(drain) => {}
+            _ = shutdown_signal() => {}
+        }
+    }
     let _ = stop_server.send(());
     Ok(())
 }
 
-fn init_logger() {
-    env_logger::builder()
+fn bind_listener(addr: SocketAddr) -> Result<TcpListener> {
+    let domain = Domain::for_address(addr);
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        // Accept IPv4-mapped connections too when bound to an IPv6 address such as `[::]`.
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|err| anyhow!("Failed to bind {addr}, {err}"))?;
+    socket.listen(1024)?;
+    let family = if addr.is_ipv6() {
+        "IPv6 (dual-stack)"
+    } else {
+        "IPv4"
+    };
+    info!("Listening on {addr} over {family}");
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+fn build_client(config: &Config) -> Result<Client> {
+    let mut client_builder = ClientBuilder::new().connect_timeout(config.connect_timeout);
+    if let Some(family) = config.upstream_ip_family {
+        client_builder = client_builder.dns_resolver(Arc::new(dns::FamilyResolver::new(family)));
+    }
+    if let Some(version) = config.upstream_min_tls {
+        client_builder = client_builder.min_tls_version(match version {
+            TlsVersion::V1_2 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::V1_3 => reqwest::tls::Version::TLS_1_3,
+        });
+    }
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        client_builder = client_builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = config.pool_idle_timeout {
+        client_builder = client_builder.pool_idle_timeout(idle_timeout);
+    }
+    if config.upstream_http2 {
+        client_builder = client_builder.http2_prior_knowledge();
+    }
+    if let Some(proxy) = &config.proxy {
+        client_builder = client_builder.proxy(
+            Proxy::all(proxy)
+                .map_err(|err| anyhow!("Invalid environment variable $ALL_PROXY, {err}"))?,
+        );
+    };
+    Ok(client_builder.build()?)
+}
+
+fn init_logger(config: &Config) -> Result<()> {
+    let mut builder = env_logger::builder();
+    builder
         .parse_env(env_logger::Env::new().filter_or("RUST_LOG", "info"))
         .format_target(false)
-        .format_module_path(false)
-        .init();
+        .format_module_path(false);
+    if let Some(path) = &config.log_file {
+        let file = RotatingFile::open(Path::new(path), config.log_max_size, config.log_max_files)
+            .map_err(|err| anyhow!("Failed to open the log file '{path}', {err}"))?;
+        let writer = LogWriter::new(file, config.log_stdout);
+        builder.target(env_logger::Target::Pipe(Box::new(writer)));
+    }
+    builder.init();
+    Ok(())
 }
 
 type AppResponse = Response<BoxBody<Bytes, Infallible>>;
 
 struct Server {
     client: Client,
-    authorization: Option<String>,
+    headers: HeaderMap,
+    config: Config,
+    /// Cancellation handles of the in-flight completions by completion id.
+    completions: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    connection_limiter: Option<ConnectionLimiter>,
+    /// Bounds the open connections, a permit is held for the lifetime of each.
+    connection_slots: Option<Arc<Semaphore>>,
+    shutting_down: AtomicBool,
+    semaphore: Option<Arc<Semaphore>>,
+    queued: AtomicUsize,
+    device_id_index: AtomicUsize,
+    /// Upstream frames of an unexpected shape since the start, reported by /ready.
+    unexpected_frames: Arc<AtomicUsize>,
+    single_flight: Option<SingleFlight>,
+    usage_webhook: Option<Arc<UsageWebhook>>,
+    prompt_hook: Option<PromptHook>,
 }
 
 impl Server {
+    fn new(config: Config) -> Result<Self> {
+        Ok(Self {
+            client: build_client(&config)?,
+            headers: common_headers(&config)?,
+            circuit_breaker: config.circuit_breaker_threshold.map(|threshold| {
+                CircuitBreaker::new(
+                    threshold,
+                    config.circuit_breaker_window,
+                    config.circuit_breaker_cooldown,
+                )
+            }),
+            connection_limiter: config
+                .max_connections_per_ip
+                .map(|v| ConnectionLimiter::new(v, config.connection_rate_window)),
+            connection_slots: config.max_connections.map(|v| Arc::new(Semaphore::new(v))),
+            semaphore: config
+                .max_concurrent_requests
+                .map(|v| Arc::new(Semaphore::new(v))),
+            queued: AtomicUsize::new(0),
+            device_id_index: AtomicUsize::new(0),
+            unexpected_frames: Default::default(),
+            single_flight: config.single_flight.then(SingleFlight::default),
+            usage_webhook: match &config.usage_webhook {
+                Some(url) => Some(Arc::new(UsageWebhook::new(url.clone())?)),
+                None => None,
+            },
+            prompt_hook: config
+                .prompt_hook
+                .clone()
+                .map(|v| PromptHook::new(v, config.prompt_hook_timeout)),
+            config,
+            completions: Default::default(),
+            shutting_down: AtomicBool::new(false),
+        })
+    }
+
     async fn run(self: Arc<Self>, listener: TcpListener) -> Result<oneshot::Sender<()>> {
         let (tx, rx) = oneshot::channel();
         tokio::spawn(async move {
             let shutdown = Shutdown::new(async { rx.await.unwrap_or_default() });
             let guard = shutdown.guard_weak();
+            let mut at_capacity = false;
 
             loop {
+                // At capacity, leave the new connections in the backlog until others close.
+                let connection_permit = match &self.connection_slots {
+                    Some(slots) => {
+                        let permit = match slots.clone().try_acquire_owned() {
+                            Ok(permit) => {
+                                at_capacity = false;
+                                permit
+                            }
+                            Err(_) => {
+                                if !at_capacity {
+                                    warn!("Reached MAX_CONNECTIONS, deferring new connections until others close");
+                                    at_capacity = true;
+                                }
+                                tokio::select! {
+                                    permit = slots.clone().acquire_owned() => permit.expect("the semaphore is never closed"),
+                                    _ = guard.cancelled() => break,
+                                }
+                            }
+                        };
+                        Some(permit)
+                    }
+                    None => None,
+                };
                 tokio::select! {
                     res = listener.accept() => {
-                        let Ok((cnx, _)) = res else {
+                        let Ok((cnx, peer)) = res else {
                             continue;
                         };
+                        if let Some(limiter) = &self.connection_limiter {
+                            if !limiter.allow(peer.ip().to_canonical()) {
+                                drop(cnx);
+                                continue;
+                            }
+                        }
+                        // The stream frames are small, send each one without waiting for the
+                        // previous one to be acknowledged.
+                        let _ = cnx.set_nodelay(true);
 
                         let stream = TokioIo::new(cnx);
                         let server = self.clone();
+                        let header_read_timeout = self.config.header_read_timeout;
                         shutdown.spawn_task(async move {
+                            let _connection_permit = connection_permit;
                             let hyper_service = service_fn(move |request: hyper::Request<Incoming>| {
-                                server.clone().handle(request)
+                                server.clone().handle(peer, request)
                             });
-                            let _ = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                            let mut builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+                            builder
+                                .http1()
+                                .timer(TokioTimer::new())
+                                .header_read_timeout(header_read_timeout);
+                            // HTTP/2 has no header timeout, ping idle peers to drop dead connections instead.
+                            builder
+                                .http2()
+                                .timer(TokioTimer::new())
+                                .keep_alive_interval(header_read_timeout)
+                                .keep_alive_timeout(header_read_timeout);
+                            let _ = builder
                                 .serve_connection_with_upgrades(stream, hyper_service)
                                 .await;
                         });
@@ -150,32 +371,99 @@ impl Server {
 
     async fn handle(
         self: Arc<Self>,
+        peer: SocketAddr,
         req: hyper::Request<Incoming>,
     ) -> std::result::Result<AppResponse, hyper::Error> {
         let method = req.method().clone();
         let uri = req.uri().clone();
-        let mut auth_failed = false;
-        if let Some(expect_authorization) = &self.authorization {
-            if let Some(authorization) = req.headers().get("authorization") {
-                if authorization.as_bytes() != expect_authorization.as_bytes() {
-                    auth_failed = true;
-                }
-            } else {
-                auth_failed = true;
+        let req_id = generate_request_id();
+        let client_ip = self.client_ip(peer.ip(), req.headers());
+        debug!("[{req_id}] {client_ip} {method} {uri} version {VERSION}");
+        // Behind a reverse proxy the routes may be mounted under `BASE_PATH`.
+        let route = strip_base_path(&self.config.base_path, &uri);
+        let in_base_path = route.is_some();
+        let route = route.unwrap_or_else(|| uri.clone());
+        // HEAD is served like GET, without the body.
+        let is_get = method == Method::GET || method == Method::HEAD;
+        let is_playground = self.config.enable_playground && is_get && route == "/";
+        let is_ready = is_get && route == "/ready";
+        // Azure OpenAI clients name the model in the path and send the key as `api-key`.
+        let azure_deployment = route
+            .path()
+            .strip_prefix("/openai/deployments/")
+            .and_then(|v| v.strip_suffix("/chat/completions"))
+            .filter(|v| self.config.azure_compat && !v.is_empty() && !v.contains('/'))
+            .map(|v| v.to_string());
+        let mut auth_error = None;
+        // The playground page is static and prompts for the authorization itself,
+        // load balancers probe the readiness without credentials.
+        let authorizations = &self.config.authorizations;
+        if !authorizations.is_empty() && !is_playground && !is_ready {
+            let api_key = req
+                .headers()
+                .get("api-key")
+                .filter(|_| self.config.azure_compat)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| format!("Bearer {v}"));
+            // Every key is compared, so the timing tells neither which one nor how much matched.
+            let accepted = |key: &[u8]| {
+                authorizations
+                    .iter()
+                    .fold(false, |found, v| found | key_matches(key, v.as_bytes()))
+            };
+            match req.headers().get("authorization") {
+                Some(authorization) if accepted(authorization.as_bytes()) => {}
+                None if api_key.as_ref().is_some_and(|v| accepted(v.as_bytes())) => {}
+                None if api_key.is_some() => auth_error = Some("Invalid api-key header value"),
+                Some(_) => auth_error = Some("Invalid Authorization header value"),
+                None => auth_error = Some("Missing Authorization header"),
             }
         }
         let mut status = StatusCode::OK;
-        let res = if auth_failed {
-            status = StatusCode::UNAUTHORIZED;
-            Err(anyhow!(
-                "No authorization header or invalid authorization value."
-            ))
-        } else if method == Method::POST && uri == "/v1/chat/completions" {
-            self.chat_completion(req).await
-        } else if method == Method::GET && uri == "/v1/models" {
+        let res = if !in_base_path {
+            status = StatusCode::NOT_FOUND;
+            Err(anyhow!("The requested endpoint was not found."))
+        } else if let Some(auth_error) = auth_error {
+            Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "authentication_error",
+                format!("{auth_error}, expected the configured key in the format 'Authorization: Bearer <key>'."),
+            )
+            .into())
+        } else if is_playground {
+            self.playground().await
+        } else if is_ready {
+            self.ready(&mut status)
+        } else if method == Method::POST && route.path() == "/v1/chat/completions" {
+            self.chat_completion(&req_id, req, None).await
+        } else if let Some(deployment) = azure_deployment
+            .as_deref()
+            .filter(|_| method == Method::POST)
+        {
+            self.chat_completion(&req_id, req, Some(deployment)).await
+        } else if method == Method::POST && route == "/v1/chat/completions/batch" {
+            self.batch_completion(&req_id, req).await
+        } else if method == Method::GET && route == "/v1/chat/completions/ws" {
+            let res = self.clone().websocket(&req_id, req);
+            if res.is_ok() {
+                status = StatusCode::SWITCHING_PROTOCOLS;
+            }
+            res
+        } else if is_get && route == "/v1/models" {
             self.models(req).await
+        } else if let Some(id) = route.path().strip_prefix("/v1/models/").filter(|_| is_get) {
+            self.model(id).await
+        } else if let Some(id) = route
+            .path()
+            .strip_prefix("/v1/chat/completions/")
+            .filter(|_| method == Method::DELETE)
+        {
+            self.cancel_completion(&req_id, id)
         } else if method == Method::OPTIONS
-            && (uri == "/v1/chat/completions" || uri == "/v1/models")
+            && (route == "/v1/chat/completions"
+                || route == "/v1/chat/completions/batch"
+                || route == "/v1/models"
+                || azure_deployment.is_some())
         {
             status = StatusCode::NO_CONTENT;
             Ok(Response::default())
@@ -185,78 +473,722 @@ impl Server {
         };
         let mut res = match res {
             Ok(res) => {
-                info!("{method} {uri} {}", status.as_u16());
+                info!("[{req_id}] {client_ip} {method} {uri} {}", status.as_u16());
                 res
             }
             Err(err) => {
-                error!("{method} {uri} {} {err}", status.as_u16());
-                create_error_response(err)
+                let (kind, debug) = match err.downcast_ref::<ApiError>() {
+                    Some(api_err) => {
+                        status = api_err.status;
+                        (api_err.kind, api_err.debug.as_ref())
+                    }
+                    None => ("invalid_request_error", None),
+                };
+                error!(
+                    "[{req_id}] {client_ip} {method} {uri} {} {err}",
+                    status.as_u16()
+                );
+                create_error_response(&err, kind, debug)
             }
         };
         *res.status_mut() = status;
+        if method == Method::HEAD {
+            *res.body_mut() = Full::new(Bytes::new()).boxed();
+        }
         set_cors_header(&mut res);
+        res.headers_mut()
+            .insert("x-api-version", HeaderValue::from_static(VERSION));
+        if let Ok(v) = HeaderValue::from_str(&req_id) {
+            res.headers_mut().insert("x-request-id", v);
+        }
         Ok(res)
     }
 
-    async fn chat_completion(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
-        let requirements = self
-            .chat_requirements()
+    async fn chat_completion(
+        self: &Arc<Self>,
+        req_id: &str,
+        req: hyper::Request<Incoming>,
+        deployment: Option<&str>,
+    ) -> Result<AppResponse> {
+        let options = self.completion_options(req.headers())?;
+
+        let accept = req
+            .headers()
+            .get("accept")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let accept_event_stream = accept
+            .as_deref()
+            .map(|v| v.contains("text/event-stream"))
+            .unwrap_or_default();
+        // Simple clients may ask for the bare answer text instead of the OpenAI envelope. An
+        // Accept that also takes JSON, like the `application/json, text/plain, */*` of many HTTP
+        // libraries, keeps the envelope.
+        let raw = accept.as_deref().is_some_and(|v| {
+            v.contains("text/plain") && !v.contains("application/json") && !v.contains("*/*")
+        }) || req.uri().query().is_some_and(|v| {
+            v.split('&')
+                .filter_map(|v| v.strip_prefix("raw="))
+                .any(|v| parse_bool(v) == Some(true))
+        });
+
+        let mut req_body = self.read_json_body(req).await?;
+        if let Some(deployment) = deployment {
+            req_body["model"] = deployment.into();
+        }
+
+        let mut is_stream = get_bool_param(&req_body, "stream")
+            .unwrap_or(accept_event_stream || self.config.default_stream);
+        if let Some(accept) = accept.as_deref().filter(|_| is_stream && !raw) {
+            let accepts_any = ["text/event-stream", "text/*", "*/*"]
+                .iter()
+                .any(|v| accept.contains(v));
+            if self.config.strict_accept && !accepts_any {
+                warn!("[{req_id}] Accept '{accept}' rejects event streams, responding without streaming");
+                is_stream = false;
+            }
+        }
+        let meta = self.completion_meta(&options, &req_body)?;
+        // The proof of work takes seconds, show the client the answer is coming meanwhile.
+        if is_stream && !raw && self.config.early_role_frame {
+            let (tx, rx) = mpsc::channel(1);
+            let (server, req_id) = (self.clone(), req_id.to_string());
+            let early_meta = Arc::new(meta.clone());
+            tokio::spawn(async move {
+                let forward = async {
+                    match server
+                        .start_completion(&req_id, &options, &req_body, meta)
+                        .await
+                    {
+                        Ok((rx, _)) => {
+                            let mut rx = server.pace_stream(rx);
+                            while let Some(event) = rx.recv().await {
+                                if tx.send(event).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            debug!("[{req_id}] Failed after the role delta was sent, {err}");
+                            let _ = tx.send(ResEvent::Error(err.to_string())).await;
+                        }
+                    }
+                };
+                // The response body going away drops the completion as it would the request
+                // future, cancelling the proof of work.
+                tokio::select! {
+                    _ = forward => {}
+                    _ = tx.closed() => debug!("[{req_id}] Client went away, cancelling the completion"),
+                }
+            });
+            return self.event_stream_response(rx, early_meta, true);
+        }
+        let (mut rx, meta) = self
+            .start_completion(req_id, &options, &req_body, meta)
             .await
-            .map_err(|err| anyhow!("Failed to meet chat requirements, {err}"))?;
+            .map_err(|err| options.attach_diagnostics(err))?;
 
-        let req_body = req.collect().await?.to_bytes();
-        let req_body: Value = serde_json::from_slice(&req_body)
-            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let fallback = meta.fallback;
+        let deadline_hit = meta.deadline_hit.clone();
+        let mut res = if is_stream && raw {
+            rx = self.pace_stream(rx);
+            let stream = ReceiverStream::new(rx).filter_map(|v| async move {
+                match v {
+                    ResEvent::Text(text) if !text.is_empty() => {
+                        Some(Ok(Frame::data(Bytes::from(text))))
+                    }
+                    ResEvent::Error(err) => {
+                        Some(Ok(Frame::data(Bytes::from(format!("\n\nError: {err}")))))
+                    }
+                    _ => None,
+                }
+            });
+            Response::builder()
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .header("Cache-Control", "no-cache")
+                .header("X-Accel-Buffering", "no")
+                .body(BodyExt::boxed(StreamBody::new(stream)))?
+        } else if is_stream {
+            self.event_stream_response(self.pace_stream(rx), meta, false)?
+        } else {
+            let body = self.collect_completion(rx, &meta, &options).await?;
+            let (content_type, body) = if raw {
+                let content = body["choices"][0]["message"]["content"].as_str();
+                let content = content.unwrap_or_default().to_string();
+                ("text/plain; charset=utf-8", Bytes::from(content))
+            } else {
+                ("application/json", Bytes::from(body.to_string()))
+            };
+            let body = if self.config.chunked_response {
+                let chunks: Vec<_> = (0..body.len())
+                    .step_by(CHUNK_SIZE)
+                    .map(|i| {
+                        Ok::<_, Infallible>(Frame::data(
+                            body.slice(i..body.len().min(i + CHUNK_SIZE)),
+                        ))
+                    })
+                    .collect();
+                BodyExt::boxed(StreamBody::new(futures_util::stream::iter(chunks)))
+            } else {
+                Full::new(body).boxed()
+            };
+            Response::builder()
+                .header("Content-Type", content_type)
+                .header(
+                    "X-Upstream-Bytes",
+                    options.upstream_bytes.load(Ordering::Relaxed),
+                )
+                .body(body)?
+        };
+        if fallback {
+            res.headers_mut()
+                .insert("x-fallback", HeaderValue::from_static("1"));
+        }
+        // Known only once the answer is complete, so never for streams.
+        if deadline_hit.load(Ordering::Relaxed) {
+            res.headers_mut()
+                .insert("x-deadline-hit", HeaderValue::from_static("1"));
+        }
+        Ok(res)
+    }
 
-        let is_stream = req_body["stream"].as_bool().unwrap_or_default();
-        let mut invalid = false;
-        let mut new_messages = vec![];
-        let mut system_prompt = None;
-        if let Some(messages) = req_body["messages"].as_array() {
-            let has_history = messages.len() > 2;
-            for v in messages {
-                let role = match v["role"].as_str() {
-                    Some(v) => v,
-                    None => {
-                        invalid = true;
-                        break;
+    /// Stream the completion as server-sent events, with the role delta already sent or not.
+    fn event_stream_response(
+        &self,
+        rx: Receiver<ResEvent>,
+        meta: Arc<CompletionMeta>,
+        early_role: bool,
+    ) -> Result<AppResponse> {
+        let stream = ReceiverStream::new(rx);
+        let default_prompt_filter = self.config.prompt_filter_results;
+        // Sent before the events, whose role delta is then dropped.
+        let early_frame = early_role.then(|| role_frame(&meta, default_prompt_filter));
+        let stream = stream.filter_map(move |v| {
+            let meta = meta.clone();
+            async move {
+                match v {
+                    ResEvent::Text(text) if text.is_empty() && early_role => None,
+                    ResEvent::Text(text) if text.is_empty() => {
+                        Some(Ok(role_frame(&meta, default_prompt_filter)))
+                    }
+                    ResEvent::Text(text) => Some(Ok(create_frame(&meta, &text, None))),
+                    // A chunk without choices, which some clients choke on, so only on request.
+                    ResEvent::Moderation(moderation) if default_prompt_filter => {
+                        let value = create_prompt_filter_chunk(&meta, Some(&moderation));
+                        Some(Ok(Frame::data(Bytes::from(format!("data: {value}\n\n")))))
+                    }
+                    ResEvent::ToolCalls(tool_calls) => {
+                        let value = create_tool_calls_chunk(&meta, tool_calls);
+                        Some(Ok(Frame::data(Bytes::from(format!("data: {value}\n\n")))))
+                    }
+                    ResEvent::Done(finish_reason) => {
+                        Some(Ok(create_frame(&meta, "", Some(finish_reason))))
+                    }
+                    ResEvent::Error(err) => Some(Ok(create_error_frame(&err, "server_error"))),
+                    _ => None,
+                }
+            }
+        });
+        let stream = futures_util::stream::iter(early_frame.map(Ok)).chain(stream);
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            // Stop nginx from buffering the stream.
+            .header("X-Accel-Buffering", "no")
+            .body(BodyExt::boxed(StreamBody::new(stream)))?)
+    }
+
+    /// Answer several independent requests at once, without streaming. The items run
+    /// concurrently and a failed item is reported in place without failing the others.
+    async fn batch_completion(
+        self: &Arc<Self>,
+        req_id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let options = self.completion_options(req.headers())?;
+        let req_body = self.read_json_body(req).await?;
+        let items = match req_body.as_array() {
+            Some(v) if !v.is_empty() => v,
+            _ => bail!("The request body must be a non-empty array of chat completion requests"),
+        };
+        if items.len() > self.config.max_batch_size {
+            bail!(
+                "Too many requests in the batch, the maximum allowed is {}",
+                self.config.max_batch_size
+            );
+        }
+        let results = items.iter().enumerate().map(|(i, item)| {
+            let (options, req_id) = (&options, format!("{req_id}-{i}"));
+            async move {
+                let result = if get_bool_param(item, "stream") == Some(true) {
+                    Err(anyhow!("Streaming is not supported in batches"))
+                } else {
+                    let started = match self.completion_meta(options, item) {
+                        Ok(meta) => self.start_completion(&req_id, options, item, meta).await,
+                        Err(err) => Err(err),
+                    };
+                    match started {
+                        Ok((rx, meta)) => self.collect_completion(rx, &meta, options).await,
+                        Err(err) => Err(options.attach_diagnostics(err)),
                     }
                 };
-                let content = {
-                    let text = match (v["content"].as_str(), v["content"].as_array()) {
-                        (Some(v), None) => v,
-                        (None, Some(arr)) => {
-                            if arr.len() == 1 {
-                                arr[0]["text"].as_str().unwrap_or_default()
-                            } else {
-                                ""
-                            }
-                        }
-                        _ => "",
+                result.unwrap_or_else(|err| {
+                    debug!("[{req_id}] batch item failed, {err}");
+                    let (kind, debug) = match err.downcast_ref::<ApiError>() {
+                        Some(api_err) => (api_err.kind, api_err.debug.clone()),
+                        None => ("invalid_request_error", None),
                     };
-                    if text.is_empty() {
-                        invalid = true;
-                        break;
+                    let mut value = create_error_value(&err.to_string(), kind);
+                    if let Some(debug) = debug {
+                        value["debug"] = debug;
                     }
-                    text
+                    value
+                })
+            }
+        });
+        let results = futures_util::future::join_all(results).await;
+        let res = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(Value::from(results).to_string())).boxed())?;
+        Ok(res)
+    }
+
+    /// Read the JSON request body, decompressing it if needed.
+    async fn read_json_body(&self, req: hyper::Request<Incoming>) -> Result<Value> {
+        let content_type = req
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !is_json_content_type(content_type) {
+            let message = if content_type.is_empty() {
+                "Missing Content-Type header, expected 'application/json'".to_string()
+            } else {
+                format!("Unsupported Content-Type '{content_type}', expected 'application/json'")
+            };
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "invalid_request_error",
+                message,
+            )
+            .into());
+        }
+
+        let content_encoding = req
+            .headers()
+            .get("content-encoding")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_lowercase());
+
+        // The limit applies while reading, an oversized body is never buffered whole.
+        let max_body_size = self.config.max_body_size;
+        let req_body = Limited::new(req.into_body(), max_body_size).collect();
+        let req_body = tokio::time::timeout(self.config.body_read_timeout, req_body)
+            .await
+            .map_err(|_| {
+                ApiError::new(
+                    StatusCode::REQUEST_TIMEOUT,
+                    "invalid_request_error",
+                    "Timed out reading the request body",
+                )
+            })?
+            .map_err(|err| match err.downcast::<LengthLimitError>() {
+                Ok(_) => body_too_large(max_body_size).into(),
+                Err(err) => anyhow!("Failed to read the request body, {err}"),
+            })?
+            .to_bytes();
+        let req_body = decode_body(
+            content_encoding.as_deref(),
+            req_body,
+            self.config.max_body_size,
+        )?;
+        let req_body = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        Ok(req_body)
+    }
+
+    /// Wait for the whole answer of a completion that is not streamed.
+    async fn collect_completion(
+        &self,
+        mut rx: Receiver<ResEvent>,
+        meta: &CompletionMeta,
+        options: &CompletionOptions,
+    ) -> Result<Value> {
+        let mut content_parts = vec![];
+        let mut tool_calls = None;
+        let mut moderation = None;
+        let mut finish_reason = "stop";
+        while let Some(event) = rx.recv().await {
+            match event {
+                ResEvent::Text(text) => {
+                    content_parts.push(text);
+                }
+                ResEvent::ToolCalls(v) => {
+                    tool_calls = Some(v);
+                }
+                ResEvent::Moderation(v) => {
+                    moderation = Some(v);
+                }
+                ResEvent::Done(reason) => {
+                    finish_reason = reason;
+                    break;
+                }
+                ResEvent::Error(err) => {
+                    let err = ApiError::new(StatusCode::BAD_GATEWAY, "server_error", err);
+                    return Err(options.attach_diagnostics(err.into()));
+                }
+                _ => {}
+            }
+        }
+        let content = content_parts.join("");
+        let mut body = create_completion(meta, &content, tool_calls, finish_reason);
+        if moderation.is_some() || self.config.prompt_filter_results {
+            body["prompt_filter_results"] = prompt_filter_results(moderation.as_ref());
+        }
+        Ok(body)
+    }
+
+    /// Upgrade to a WebSocket that answers each request message with the streamed chunks.
+    fn websocket(
+        self: Arc<Self>,
+        req_id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let is_upgrade = req
+            .headers()
+            .get("upgrade")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+        let key = req
+            .headers()
+            .get("sec-websocket-key")
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| is_upgrade)
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "Expected a WebSocket upgrade request",
+                )
+            })?;
+        let accept_key = websocket::accept_key(key);
+        // Refuse the upgrade rather than failing every message of the session.
+        let options = self.completion_options(req.headers()).map_err(|err| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                err.to_string(),
+            )
+        })?;
+        let req_id = req_id.to_string();
+        tokio::spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    let ws = WebSocket::new(TokioIo::new(upgraded));
+                    if let Err(err) = self.websocket_session(&req_id, ws, options).await {
+                        warn!("[{req_id}] WebSocket session failed, {err}");
+                    }
+                }
+                Err(err) => warn!("[{req_id}] WebSocket upgrade failed, {err}"),
+            }
+        });
+        let res = Response::builder()
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", accept_key)
+            .body(Full::new(Bytes::new()).boxed())?;
+        Ok(res)
+    }
+
+    async fn websocket_session<S>(
+        self: &Arc<Self>,
+        req_id: &str,
+        mut ws: WebSocket<S>,
+        mut options: CompletionOptions,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        while let Some(message) = ws.recv().await? {
+            // Every message is a request of its own.
+            options.deadline = self.request_deadline();
+            options.device_id = self.next_device_id();
+            let started = match serde_json::from_str::<Value>(&message) {
+                Ok(req_body) => match self.completion_meta(&options, &req_body) {
+                    Ok(meta) => {
+                        self.start_completion(req_id, &options, &req_body, meta)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(anyhow!("Invalid request message, {err}")),
+            };
+            let (rx, meta) = match started {
+                Ok(v) => v,
+                Err(err) => {
+                    let value = create_error_value(&err.to_string(), "invalid_request_error");
+                    ws.send(&value.to_string()).await?;
+                    continue;
+                }
+            };
+            let mut rx = self.pace_stream(rx);
+            while let Some(event) = rx.recv().await {
+                let value = match event {
+                    ResEvent::Text(text) => create_chunk(&meta, &text, None),
+                    ResEvent::ToolCalls(tool_calls) => create_tool_calls_chunk(&meta, tool_calls),
+                    ResEvent::Moderation(v) if self.config.prompt_filter_results => {
+                        create_prompt_filter_chunk(&meta, Some(&v))
+                    }
+                    ResEvent::Done(finish_reason) => create_chunk(&meta, "", Some(finish_reason)),
+                    ResEvent::Error(err) => create_error_value(&err, "server_error"),
+                    _ => continue,
                 };
-                if role == "system" {
-                    if system_prompt.is_some() {
-                        invalid = true;
-                        break;
+                ws.send(&value.to_string()).await?;
+            }
+            ws.send("[DONE]").await?;
+        }
+        Ok(())
+    }
+
+    /// Resolve the address of the client. The forwarding headers are only read when the peer is
+    /// a trusted proxy, the rightmost untrusted hop of `X-Forwarded-For` is the client.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let is_trusted = |ip: &IpAddr| {
+            let ip = ip.to_canonical();
+            self.config.trusted_proxies.iter().any(|v| v.contains(&ip))
+        };
+        if !is_trusted(&peer) {
+            return peer;
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|v| v.trim().parse().ok())
+            .collect();
+        if let Some(ip) = forwarded
+            .iter()
+            .rev()
+            .find(|v| !is_trusted(v))
+            .or(forwarded.first())
+        {
+            return *ip;
+        }
+        headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+
+    /// Read the per-request overrides of the configuration from the headers.
+    fn completion_options(&self, headers: &HeaderMap) -> Result<CompletionOptions> {
+        let upstream_timeout = match headers.get("x-upstream-timeout-ms") {
+            Some(v) => {
+                let timeout = v
+                    .to_str()
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .ok_or_else(|| anyhow!("Invalid X-Upstream-Timeout-Ms header"))?;
+                Some(Duration::from_millis(
+                    timeout.min(self.config.max_upstream_timeout_ms),
+                ))
+            }
+            None => self.config.upstream_timeout,
+        };
+
+        let history_disabled = match headers.get("x-history-disabled") {
+            Some(v) => v
+                .to_str()
+                .ok()
+                .and_then(parse_bool)
+                .ok_or_else(|| anyhow!("Invalid X-History-Disabled header"))?,
+            None => self.config.history_disabled,
+        };
+
+        let strip_markdown = match headers.get("x-strip-markdown") {
+            Some(v) => v
+                .to_str()
+                .ok()
+                .and_then(parse_bool)
+                .ok_or_else(|| anyhow!("Invalid X-Strip-Markdown header"))?,
+            None => self.config.strip_markdown,
+        };
+
+        let system_prompt = match headers.get("x-system-prompt") {
+            Some(v) => Some(
+                String::from_utf8(v.as_bytes().to_vec())
+                    .map_err(|_| anyhow!("Invalid X-System-Prompt header"))?,
+            )
+            .filter(|v| !v.trim().is_empty()),
+            None => None,
+        };
+
+        let final_only = match headers.get("x-final-only") {
+            Some(v) => v
+                .to_str()
+                .ok()
+                .and_then(parse_bool)
+                .ok_or_else(|| anyhow!("Invalid X-Final-Only header"))?,
+            None => false,
+        };
+
+        let debug = self.config.debug_header
+            && headers
+                .get("x-debug")
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_bool)
+                .unwrap_or_default();
+        Ok(CompletionOptions {
+            upstream_timeout,
+            history_disabled,
+            strip_markdown,
+            system_prompt,
+            final_only,
+            device_id: self.next_device_id(),
+            diagnostics: debug.then(Default::default),
+            deadline: self.request_deadline(),
+            upstream_bytes: Default::default(),
+        })
+    }
+
+    fn request_deadline(&self) -> Option<tokio::time::Instant> {
+        let deadline = self.config.request_deadline?;
+        Some(tokio::time::Instant::now() + deadline)
+    }
+
+    /// Rotate through the configured device ids.
+    fn next_device_id(&self) -> Option<String> {
+        let device_ids = &self.config.oai_device_ids;
+        if device_ids.is_empty() {
+            return None;
+        }
+        let index = self.device_id_index.fetch_add(1, Ordering::Relaxed);
+        Some(device_ids[index % device_ids.len()].clone())
+    }
+
+    /// Validate the messages, send them upstream and return the transformed events.
+    async fn start_completion(
+        self: &Arc<Self>,
+        req_id: &str,
+        options: &CompletionOptions,
+        req_body: &Value,
+        mut meta: CompletionMeta,
+    ) -> Result<(Receiver<ResEvent>, Arc<CompletionMeta>)> {
+        if self.config.strict_schema {
+            let errors = schema::validate_chat_request(req_body);
+            if !errors.is_empty() {
+                bail!("Invalid request, {}", errors.join("; "));
+            }
+        }
+        let seed = meta.seed;
+        let user = get_param(req_body, "user").as_str().map(|v| v.to_string());
+        let echo = get_bool_param(req_body, "echo").unwrap_or_default();
+        let response_format = get_param(req_body, "response_format");
+        let response_schema = match response_format["type"].as_str() {
+            Some("json_schema") => match &response_format["json_schema"]["schema"] {
+                Value::Object(v) => Some(Value::Object(v.clone())),
+                _ => bail!("'response_format.json_schema.schema' must be an object"),
+            },
+            _ => None,
+        };
+        let mut prompt = None;
+        let started = Instant::now();
+        let mut new_messages = vec![];
+        let mut system_prompt = None;
+        let messages = match req_body["messages"].as_array() {
+            Some(v) if !v.is_empty() => v,
+            _ => bail!("'messages' is required and must be a non-empty array"),
+        };
+        if messages.len() > self.config.max_messages {
+            bail!(
+                "Too many messages, the maximum allowed is {}",
+                self.config.max_messages
+            );
+        }
+        let has_history = messages.len() > 2;
+        for (i, v) in messages.iter().enumerate() {
+            let Some(role) = v["role"].as_str() else {
+                bail!("Invalid request messages, messages[{i}].role must be a string");
+            };
+            let content = {
+                let text = match &v["content"] {
+                    Value::String(v) => v.clone(),
+                    Value::Array(arr) if arr.len() == 1 => match arr[0]["text"].as_str() {
+                        Some(text) => text.to_string(),
+                        None => bail!(
+                            "Invalid request messages, messages[{i}].content[0] must be a text part like {{\"type\": \"text\", \"text\": \"...\"}}"
+                        ),
+                    },
+                    Value::Array(arr) if arr.len() > 1 => bail!(
+                        "Invalid request messages, messages[{i}].content has {} parts, only a single text part is supported",
+                        arr.len()
+                    ),
+                    // Some buggy clients send scalar content, coerce it to text.
+                    Value::Number(v) => v.to_string(),
+                    Value::Bool(v) => v.to_string(),
+                    // An assistant turn that only called tools.
+                    Value::Null if role == "assistant" => describe_tool_calls(v),
+                    Value::Object(_) => bail!(
+                        "Invalid request messages, messages[{i}].content must be a string or an array with a single text part, not an object"
+                    ),
+                    _ => String::new(),
+                };
+                if text.is_empty() {
+                    bail!("Invalid request messages, messages[{i}].content must not be empty");
+                }
+                // The upstream has no tool turns, their results are passed on as context.
+                if role == "tool" || role == "function" {
+                    let name = v["name"].as_str().or(v["tool_call_id"].as_str());
+                    match name {
+                        Some(name) => format!("Result of the {name} call:\n{text}"),
+                        None => format!("Result of the tool call:\n{text}"),
                     }
-                    system_prompt = Some(content.to_string());
-                } else if role == "user" && has_history {
-                    new_messages.push(format!("[INST]{content}[/INST]"));
                 } else {
-                    new_messages.push(content.to_string());
+                    text
+                }
+            };
+            if role == "user" {
+                prompt = Some(content.clone());
+            }
+            if role == "system" {
+                if system_prompt.is_some() {
+                    bail!("Invalid request messages, messages[{i}] is a second system message, only one is supported");
                 }
+                system_prompt = Some(content);
+            } else if let Some(template) = &self.config.message_template {
+                new_messages.push(
+                    template
+                        .replace("{role}", &role_label(role))
+                        .replace("{content}", &content),
+                );
+            } else if role == "user" && has_history {
+                new_messages.push(format!("[INST]{content}[/INST]"));
+            } else {
+                new_messages.push(content);
             }
         }
 
-        if invalid {
-            bail!("Invalid request messages");
-        }
+        // The configured and per-request system prompts come before the one of the messages.
+        let system_prompts: Vec<String> = [
+            self.config.system_prompt.clone(),
+            options.system_prompt.clone(),
+            system_prompt,
+            response_schema.as_ref().map(|schema| {
+                format!("Respond only with JSON that conforms to the following JSON Schema, without any other text or markdown:\n{schema}")
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let system_prompt = (!system_prompts.is_empty()).then(|| system_prompts.join("\n\n"));
+        let combine_message = new_messages.join(&self.config.message_separator);
+        let (system_prompt, combine_message) = match &self.prompt_hook {
+            Some(hook) => hook.run(req_id, system_prompt, combine_message).await,
+            None => (system_prompt, combine_message),
+        };
 
         let mut messages = vec![];
         if let Some(system_prompt) = system_prompt {
@@ -268,7 +1200,20 @@ impl Server {
             }))
         }
 
-        let combine_message = new_messages.join("\n");
+        // Everything that makes two requests send the same conversation upstream.
+        let flight_key = self.single_flight.as_ref().map(|_| {
+            let system_prompt: Vec<&Value> = messages.iter().map(|v| &v["content"]).collect();
+            let key = json!([
+                system_prompt,
+                combine_message,
+                seed,
+                options.history_disabled,
+                // Only final answers carry the snapshots of rewrites.
+                options.final_only,
+                response_schema,
+            ]);
+            hex_encode(digest(&SHA256, key.to_string().as_bytes()).as_ref())
+        });
         messages.push(json!({
             "id": random_id(),
             "author": { "role": "user" },
@@ -276,14 +1221,14 @@ impl Server {
             "metadata": {},
         }));
 
-        let req_body = json!({
+        let mut req_body = json!({
             "action": "next",
             "messages": messages,
             "parent_message_id": random_id(),
             "model": "text-davinci-002-render-sha",
             "timezone_offset_min": 0,
             "suggestions": [],
-            "history_and_training_disabled": true,
+            "history_and_training_disabled": options.history_disabled,
             "conversation_mode": { "kind": "primary_assistant" },
             "force_paragen": false,
             "force_paragen_model_slug": "",
@@ -291,180 +1236,909 @@ impl Server {
             "force_rate_limit":false,
             "websocket_request_id": random_id(),
         });
+        if let Some(seed) = seed {
+            req_body["seed"] = seed.into();
+        }
 
-        let proof_token = calculate_proof_token(&requirements.seed, &requirements.difficulty);
-        debug!("headers: oai_device_id {}; openai-sentinel-chat-requirements-token {}; openai-sentinel-proof-token {proof_token}", requirements.oai_device_id, requirements.token);
-        debug!("req body: {req_body}");
+        let completion_id = meta.id.clone();
+        let mut fallback = false;
+        let mut rx = if let Some(word) = self.find_blocked_word(&combine_message) {
+            info!("[{req_id}] Refused a prompt containing the blocked word '{word}'");
+            let (tx, rx) = mpsc::channel(2);
+            let _ = tx.send(ResEvent::Text(String::new())).await;
+            let _ = tx.send(ResEvent::Done("content_filter")).await;
+            rx
+        } else if let Some(retry_after) =
+            self.circuit_breaker.as_ref().and_then(|v| v.allow().err())
+        {
+            if !self.config.fallback_enabled {
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "service_unavailable",
+                    format!(
+                        "The upstream keeps failing, retry in {}s",
+                        retry_after.as_secs().max(1)
+                    ),
+                )
+                .into());
+            }
+            warn!("[{req_id}] The upstream keeps failing, serving the fallback message");
+            fallback = true;
+            let (tx, rx) = mpsc::channel(3);
+            let _ = tx.send(ResEvent::Text(String::new())).await;
+            let message = self.config.fallback_message.clone();
+            let _ = tx.send(ResEvent::Text(message)).await;
+            let _ = tx.send(ResEvent::Done("stop")).await;
+            rx
+        } else {
+            let flight = match (&self.single_flight, &flight_key) {
+                (Some(single_flight), Some(key)) => Some(single_flight.join(key)),
+                _ => None,
+            };
+            let rx = match flight {
+                Some(Join::Follower(rx)) => {
+                    info!("[{req_id}] Sharing the upstream completion of an identical request");
+                    Ok(rx)
+                }
+                // Shared, the completion must go on when this request goes away.
+                Some(Join::Leader(leader)) => {
+                    let (server, req_id) = (self.clone(), req_id.to_string());
+                    let (options, completion_id) = (options.clone(), completion_id.clone());
+                    leader
+                        .lead(async move {
+                            server
+                                .timed_completion(
+                                    &req_id,
+                                    req_body,
+                                    &options,
+                                    &completion_id,
+                                    response_schema.as_ref(),
+                                )
+                                .await
+                        })
+                        .await
+                }
+                None => {
+                    self.timed_completion(
+                        req_id,
+                        req_body,
+                        options,
+                        &completion_id,
+                        response_schema.as_ref(),
+                    )
+                    .await
+                }
+            };
+            if let (Some(webhook), Err(err)) = (&self.usage_webhook, &rx) {
+                let payload = usage_payload(
+                    req_id,
+                    &completion_id,
+                    user.as_deref(),
+                    started,
+                    Err(err.to_string()),
+                );
+                webhook.send(req_id, payload);
+            }
+            rx?
+        };
+        if let Some(deadline) = options.deadline {
+            rx = transform::deadline(rx, deadline, meta.deadline_hit.clone());
+        }
+        // Before the transforms of the text, which cannot take a snapshot.
+        if options.final_only {
+            rx = transform::settle(rx);
+        }
+        if let Some(max_chars) = self.config.max_response_chars {
+            rx = transform::truncate(rx, max_chars);
+        }
+        if options.strip_markdown {
+            rx = transform::strip_markdown(rx);
+        }
+        if self.config.response_prefix.is_some() || self.config.response_suffix.is_some() {
+            rx = transform::wrap(
+                rx,
+                self.config.response_prefix.clone(),
+                self.config.response_suffix.clone(),
+            );
+        }
+        // Like the legacy completions, `echo` starts the answer with the prompt.
+        if let Some(prompt) = prompt.filter(|_| echo) {
+            rx = transform::wrap(rx, Some(prompt), None);
+        }
+        if let Some(webhook) = self.usage_webhook.clone() {
+            let (req_id, completion_id) = (req_id.to_string(), completion_id.clone());
+            rx = transform::on_finish(rx, move |outcome| {
+                let payload =
+                    usage_payload(&req_id, &completion_id, user.as_deref(), started, outcome);
+                webhook.send(&req_id, payload);
+            });
+        }
 
-        let mut es = self
-            .client
-            .post(CONVERSATION_URL)
-            .headers(common_headers())
-            .header("oai-device-id", requirements.oai_device_id)
-            .header(
-                "openai-sentinel-chat-requirements-token",
-                requirements.token,
+        meta.fallback = fallback;
+        Ok((rx, Arc::new(meta)))
+    }
+
+    /// Describe the completion to its chunks, before any of them is sent.
+    fn completion_meta(
+        &self,
+        options: &CompletionOptions,
+        req_body: &Value,
+    ) -> Result<CompletionMeta> {
+        // Real logprobs are unavailable, but strict clients expect the requested field.
+        let logprobs = get_bool_param(req_body, "logprobs").unwrap_or_default();
+        // Only echoed back like `store`, which is ignored, the upstream knows neither.
+        let metadata = match get_param(req_body, "metadata") {
+            Value::Null => None,
+            Value::Object(v) => Some(Value::Object(v.clone())),
+            _ => bail!("'metadata' must be an object"),
+        };
+        Ok(CompletionMeta {
+            id: generate_completion_id(&self.config.completion_id_prefix),
+            // Like OpenAI, `created` is the time the completion started and is shared by all chunks.
+            created: Utc::now().timestamp(),
+            system_fingerprint: self.config.system_fingerprint.clone(),
+            logprobs,
+            seed: get_param(req_body, "seed").as_i64(),
+            metadata,
+            fallback: false,
+            deadline_hit: Default::default(),
+            timing: options.diagnostics.is_some().then(Instant::now),
+        })
+    }
+
+    /// Ask the upstream for the answer, in the requested format, before the request deadline.
+    async fn timed_completion(
+        &self,
+        req_id: &str,
+        req_body: Value,
+        options: &CompletionOptions,
+        completion_id: &str,
+        response_schema: Option<&Value>,
+    ) -> Result<Receiver<ResEvent>> {
+        let completion = async {
+            match response_schema {
+                Some(schema) => {
+                    self.json_completion(req_id, &req_body, options, completion_id, schema)
+                        .await
+                }
+                None => {
+                    self.upstream_completion(req_id, req_body, options, completion_id)
+                        .await
+                }
+            }
+        };
+        match options.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, completion)
+                .await
+                .unwrap_or_else(|_| {
+                    warn!("[{req_id}] The request deadline passed before the upstream answered");
+                    Err(ApiError::new(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "timeout",
+                        "The request deadline passed before the upstream answered",
+                    )
+                    .into())
+                }),
+            None => completion.await,
+        }
+    }
+
+    async fn upstream_completion(
+        &self,
+        req_id: &str,
+        req_body: Value,
+        options: &CompletionOptions,
+        completion_id: &str,
+    ) -> Result<Receiver<ResEvent>> {
+        let permit = self.acquire_permit(req_id).await?;
+        let completion_cancel = self.track_completion(completion_id);
+        let rx = self
+            .conversation(req_id, req_body, options, completion_cancel)
+            .await;
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            match rx {
+                Ok(_) => circuit_breaker.record_success(),
+                Err(_) => circuit_breaker.record_failure(),
+            }
+        }
+        Ok(match permit {
+            Some(permit) => transform::hold(rx?, permit),
+            None => rx?,
+        })
+    }
+
+    /// Assemble the whole answer to check it against the `json_schema` response format before
+    /// anything is sent, asking the upstream once more when it does not conform.
+    async fn json_completion(
+        &self,
+        req_id: &str,
+        req_body: &Value,
+        options: &CompletionOptions,
+        completion_id: &str,
+        schema: &Value,
+    ) -> Result<Receiver<ResEvent>> {
+        let mut errors = vec![];
+        for attempt in 1..=JSON_SCHEMA_ATTEMPTS {
+            let mut rx = self
+                .upstream_completion(req_id, req_body.clone(), options, completion_id)
+                .await?;
+            let mut text = String::new();
+            let mut finish_reason = "stop";
+            while let Some(event) = rx.recv().await {
+                match event {
+                    ResEvent::Text(v) => text.push_str(&v),
+                    ResEvent::Snapshot(v) => text = v,
+                    ResEvent::Done(v) => {
+                        finish_reason = v;
+                        break;
+                    }
+                    ResEvent::Error(err) => {
+                        return Err(
+                            ApiError::new(StatusCode::BAD_GATEWAY, "server_error", err).into()
+                        )
+                    }
+                    _ => {}
+                }
+            }
+            match check_json_answer(&text, schema) {
+                Ok(answer) => {
+                    let (tx, rx) = mpsc::channel(3);
+                    let _ = tx.send(ResEvent::Text(String::new())).await;
+                    let _ = tx.send(ResEvent::Text(answer)).await;
+                    let _ = tx.send(ResEvent::Done(finish_reason)).await;
+                    return Ok(rx);
+                }
+                Err(err) => {
+                    warn!(
+                        "[{req_id}] The answer does not match the json_schema, attempt {attempt}, {}",
+                        err.join("; ")
+                    );
+                    errors = err;
+                }
+            }
+        }
+        Err(ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "server_error",
+            format!(
+                "The upstream answer does not match the json_schema response format, {}",
+                errors.join("; ")
+            ),
+        )
+        .into())
+    }
+
+    /// Batch and pace the events of a streamed completion as configured, keeping them in order.
+    fn pace_stream(&self, mut rx: Receiver<ResEvent>) -> Receiver<ResEvent> {
+        let config = &self.config;
+        if config.coalesce_chars.is_some() || config.coalesce_interval.is_some() {
+            rx = transform::coalesce(rx, config.coalesce_chars, config.coalesce_interval);
+        }
+        if let Some(min_interval) = config.min_frame_interval {
+            rx = transform::pace(rx, min_interval);
+        }
+        if config.stream_error_events {
+            rx = transform::fail_interrupted(rx);
+        }
+        transform::order(rx)
+    }
+
+    /// Send the conversation upstream and return its events once the first one has arrived.
+    async fn conversation(
+        &self,
+        req_id: &str,
+        req_body: Value,
+        options: &CompletionOptions,
+        completion_cancel: CompletionCancel,
+    ) -> Result<Receiver<ResEvent>> {
+        let upstream_timeout = options.upstream_timeout;
+        let diagnostics = options.diagnostics.clone();
+        let requirements = self
+            .chat_requirements(
+                req_id,
+                upstream_timeout,
+                options.device_id.as_deref(),
+                &diagnostics,
+            )
+            .await
+            .map_err(|err| match err.downcast::<ApiError>() {
+                Ok(err) => err.into(),
+                Err(err) => anyhow!("Failed to meet chat requirements, {err}"),
+            })?;
+
+        // Dropping the request future (e.g. the client disconnected) cancels the proof of work.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let _cancel_guard = CancelOnDrop(cancel.clone());
+        let proof_token = if self.config.disable_pow {
+            None
+        } else {
+            Some(
+                solve_proof_token(
+                    req_id,
+                    &self.config.pow_format,
+                    &requirements,
+                    self.config.pow_threads,
+                    cancel.clone(),
+                    &diagnostics,
+                )
+                .await?,
             )
-            .header("openai-sentinel-proof-token", proof_token)
-            .json(&req_body)
-            .eventsource()?;
+        };
+        debug!(
+            "[{req_id}] headers: oai_device_id {}; openai-sentinel-chat-requirements-token {}; openai-sentinel-proof-token {}",
+            requirements.oai_device_id,
+            requirements.token,
+            proof_token.as_deref().unwrap_or("-")
+        );
+        debug!("[{req_id}] req body: {req_body}");
+
+        let client = self.client.clone();
+        let conversation_url = format!("{}{CONVERSATION_PATH}", self.config.upstream_base_url);
+        let headers = self.headers.clone();
+        let mut es = conversation_eventsource(
+            &client,
+            &conversation_url,
+            &headers,
+            &requirements,
+            proof_token.as_deref(),
+            &req_body,
+            self.config.max_frame_size,
+        );
 
         let (tx, mut rx) = mpsc::channel(1);
+        let deadline = self
+            .config
+            .max_completion
+            .map(|v| tokio::time::Instant::now() + v);
 
+        let auto_resume = self.config.auto_resume;
+        let pow_threads = self.config.pow_threads;
+        let pow_format = self.config.pow_format.clone();
+        let max_frame_size = self.config.max_frame_size;
+        let max_upstream_bytes = self.config.max_upstream_bytes;
+        let reported_bytes = options.upstream_bytes.clone();
+        let final_only = options.final_only;
+        let total_unexpected_frames = self.unexpected_frames.clone();
+        let req_id = req_id.to_string();
         tokio::spawn(async move {
+            let mut proof_token = proof_token;
+            let mut proof_sent = proof_token.is_some();
+            let mut resumed = false;
+            let mut refused = false;
+            let mut tool_calls = None;
+            let mut role_sent = false;
+            let CompletionCancel {
+                rx: mut cancel_rx,
+                _guard,
+            } = completion_cancel;
             let mut check = true;
             let mut prev_text_size = 0;
-            while let Some(event) = es.next().await {
+            let mut sent_text = String::new();
+            // Consecutive assistant messages without text, a known upstream failure when nothing
+            // else ever arrives.
+            let mut empty_messages = 0;
+            // Frames that may carry content in a shape the parser does not know.
+            let mut unexpected_frames = 0;
+            let mut upstream_bytes = 0;
+            loop {
+                let next_event = async {
+                    match upstream_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, es.next())
+                            .await
+                            .map_err(|_| timeout),
+                        None => Ok(es.next().await),
+                    }
+                };
+                let event = tokio::select! {
+                    event = next_event => event,
+                    _ = sleep_until(deadline) => {
+                        debug!("[{req_id}] Completion exceeded the maximum duration, truncating");
+                        es.close();
+                        send_first_event(tx.clone(), None, &mut check).await;
+                        let event = if prev_text_size == 0 {
+                            ResEvent::Error(EMPTY_CONTENT_ERROR.to_string())
+                        } else {
+                            ResEvent::Done("length")
+                        };
+                        let _ = tx.send(event).await;
+                        break;
+                    }
+                    Ok(()) = &mut cancel_rx => {
+                        debug!("[{req_id}] Completion was cancelled");
+                        es.close();
+                        send_first_event(tx.clone(), None, &mut check).await;
+                        let event = if prev_text_size == 0 {
+                            ResEvent::Error("The completion was cancelled".to_string())
+                        } else {
+                            ResEvent::Done("stop")
+                        };
+                        let _ = tx.send(event).await;
+                        break;
+                    }
+                };
+                let event = match event {
+                    Ok(event) => event,
+                    Err(timeout) => {
+                        let err = format!(
+                            "Upstream timed out after {}ms without data",
+                            timeout.as_millis()
+                        );
+                        send_error_event(&req_id, tx.clone(), err, &mut check, prev_text_size)
+                            .await;
+                        es.close();
+                        break;
+                    }
+                };
+                let Some(event) = event else {
+                    break;
+                };
                 match event {
                     Ok(Event::Open) => {}
                     Ok(Event::Message(message)) => {
+                        upstream_bytes += message.data.len();
+                        reported_bytes.fetch_add(message.data.len(), Ordering::Relaxed);
+                        if let Some(max) = max_upstream_bytes.filter(|v| upstream_bytes > *v) {
+                            es.close();
+                            warn!("[{req_id}] Upstream sent more than {max} bytes, truncating after {prev_text_size} chars");
+                            send_first_event(tx.clone(), None, &mut check).await;
+                            let event = if prev_text_size == 0 {
+                                ResEvent::Error(EMPTY_CONTENT_ERROR.to_string())
+                            } else {
+                                ResEvent::Done("length")
+                            };
+                            let _ = tx.send(event).await;
+                            break;
+                        }
                         send_first_event(tx.clone(), None, &mut check).await;
                         if message.data == "[DONE]" {
-                            let _ = tx.send(ResEvent::Done).await;
+                            if let Some(tool_calls) = tool_calls.take() {
+                                if !role_sent {
+                                    let _ = tx.send(ResEvent::Text(String::new())).await;
+                                }
+                                let _ = tx.send(ResEvent::ToolCalls(tool_calls)).await;
+                                let _ = tx.send(ResEvent::Done("tool_calls")).await;
+                            } else if refused {
+                                if !role_sent {
+                                    let _ = tx.send(ResEvent::Text(String::new())).await;
+                                }
+                                let _ = tx.send(ResEvent::Done("content_filter")).await;
+                            } else if prev_text_size == 0 {
+                                let err = empty_content_error(&req_id, empty_messages);
+                                let _ = tx.send(ResEvent::Error(err)).await;
+                            } else {
+                                let _ = tx.send(ResEvent::Done("stop")).await;
+                            }
                             break;
                         }
                         if let Ok(data) = serde_json::from_str::<Value>(&message.data) {
+                            if !refused && is_refusal(&data) {
+                                debug!("[{req_id}] Upstream flagged the answer as a refusal");
+                                refused = true;
+                            }
+                            if data["moderation_response"].is_object() {
+                                let moderation = data["moderation_response"].clone();
+                                let _ = tx.send(ResEvent::Moderation(moderation)).await;
+                            }
+                            // The message is a snapshot, the complete tool calls are sent at the end.
+                            if let Some(v) = parse_tool_calls(&data["message"]) {
+                                tool_calls = Some(v);
+                            }
                             if let (Some("assistant"), Some(text)) = (
                                 data["message"]["author"]["role"].as_str(),
                                 data["message"]["content"]["parts"][0].as_str(),
                             ) {
+                                // A resumed answer that does not repeat what was sent cannot be
+                                // continued without garbling it, the answer ends there instead.
+                                if resumed
+                                    && !text.starts_with(&sent_text)
+                                    && !sent_text.starts_with(text)
+                                {
+                                    warn!("[{req_id}] The resumed answer differs from the {prev_text_size} chars already sent, ending the answer there");
+                                    es.close();
+                                    let _ = tx.send(ResEvent::Done("stop")).await;
+                                    break;
+                                }
+                                if text.is_empty() {
+                                    empty_messages += 1;
+                                } else {
+                                    empty_messages = 0;
+                                }
+                                // The deltas cannot take back a rewrite of the text already sent,
+                                // the whole answer can when it is only sent at the end.
+                                if final_only && prev_text_size > 0 && !text.starts_with(&sent_text)
+                                {
+                                    let snapshot = ResEvent::Snapshot(text.to_string());
+                                    if tx.send(snapshot).await.is_err() {
+                                        es.close();
+                                        break;
+                                    }
+                                    prev_text_size = text.chars().count();
+                                    sent_text = text.to_string();
+                                    continue;
+                                }
                                 let trimed_text: String =
                                     text.chars().skip(prev_text_size).collect();
                                 if trimed_text.is_empty() && prev_text_size > 0 {
                                     continue;
                                 }
-                                let _ = tx.send(ResEvent::Text(trimed_text)).await;
+                                if tx.send(ResEvent::Text(trimed_text)).await.is_err() {
+                                    debug!("[{req_id}] Completion receiver dropped, closing the upstream");
+                                    es.close();
+                                    break;
+                                }
+                                role_sent = true;
                                 prev_text_size = text.chars().count();
+                                sent_text = text.to_string();
+                            } else if is_unexpected_message(&data["message"]) {
+                                unexpected_frames += 1;
+                                total_unexpected_frames.fetch_add(1, Ordering::Relaxed);
+                                let message = redact_frame(&data["message"]);
+                                debug!("[{req_id}] Ignored an upstream message of unexpected shape, {}", snippet(&message.to_string()));
                             }
-                        };
+                        } else {
+                            unexpected_frames += 1;
+                            total_unexpected_frames.fetch_add(1, Ordering::Relaxed);
+                            debug!(
+                                "[{req_id}] Ignored a {} byte upstream frame that is not JSON",
+                                message.data.len()
+                            );
+                        }
                     }
                     Err(err) => {
                         match err {
-                            EventSourceError::StreamEnded => {}
-                            EventSourceError::InvalidStatusCode(_, res) => {
-                                let status = res.status().as_u16();
-                                let data = match res.text().await {
+                            EventSourceError::StreamEnded => {
+                                let err = if prev_text_size == 0 && empty_messages > 0 {
+                                    empty_content_error(&req_id, empty_messages)
+                                } else {
+                                    "Upstream stream ended unexpectedly".to_string()
+                                };
+                                send_error_event(
+                                    &req_id,
+                                    tx.clone(),
+                                    err,
+                                    &mut check,
+                                    prev_text_size,
+                                )
+                                .await;
+                            }
+                            EventSourceError::InvalidStatusCode(status, _)
+                                if status == StatusCode::FORBIDDEN && !proof_sent =>
+                            {
+                                es.close();
+                                proof_sent = true;
+                                debug!("[{req_id}] Conversation was forbidden without proof of work, retrying with proof of work");
+                                match solve_proof_token(
+                                    &req_id,
+                                    &pow_format,
+                                    &requirements,
+                                    pow_threads,
+                                    cancel.clone(),
+                                    &diagnostics,
+                                )
+                                .await
+                                {
+                                    Ok(v) => {
+                                        es = conversation_eventsource(
+                                            &client,
+                                            &conversation_url,
+                                            &headers,
+                                            &requirements,
+                                            Some(&v),
+                                            &req_body,
+                                            max_frame_size,
+                                        );
+                                        proof_token = Some(v);
+                                        continue;
+                                    }
+                                    Err(err) => {
+                                        send_error_event(
+                                            &req_id,
+                                            tx.clone(),
+                                            err.to_string(),
+                                            &mut check,
+                                            prev_text_size,
+                                        )
+                                        .await;
+                                    }
+                                }
+                            }
+                            EventSourceError::Transport(err) if auto_resume && !resumed => {
+                                es.close();
+                                resumed = true;
+                                // The resumed answer is regenerated from scratch, the accumulated
+                                // text diff skips the characters that were already sent.
+                                warn!("[{req_id}] Upstream connection broke after {prev_text_size} chars, resuming, {err}");
+                                es = conversation_eventsource(
+                                    &client,
+                                    &conversation_url,
+                                    &headers,
+                                    &requirements,
+                                    proof_token.as_deref(),
+                                    &req_body,
+                                    max_frame_size,
+                                );
+                                continue;
+                            }
+                            EventSourceError::InvalidStatusCode(status, res) => {
+                                let text = res.text().await;
+                                record_diagnostics(&diagnostics, |v| {
+                                    v.upstream_status = Some(status.as_u16());
+                                    v.upstream_body = text.as_deref().ok().map(snippet);
+                                });
+                                let data = match text {
+                                    Ok(v) if is_cloudflare_challenge(status, &v) => {
+                                        warn!("[{req_id}] Upstream returned a Cloudflare challenge, {}", snippet(&v));
+                                        CLOUDFLARE_CHALLENGE_ERROR.to_string()
+                                    }
                                     Ok(v) => format!("Invalid response code {status}, {v}"),
                                     Err(err) => format!("Invalid response, code {status}, {err}"),
                                 };
-                                send_first_event(tx.clone(), Some(data), &mut check).await;
+                                send_error_event(
+                                    &req_id,
+                                    tx.clone(),
+                                    data,
+                                    &mut check,
+                                    prev_text_size,
+                                )
+                                .await;
                             }
                             EventSourceError::InvalidContentType(_, res) => {
                                 let text = res.text().await.unwrap_or_default();
                                 let err = format!("The chatgpt api should return data as 'text/event-stream', but it isn't. {text}");
-                                send_first_event(tx.clone(), Some(err), &mut check).await;
+                                send_error_event(
+                                    &req_id,
+                                    tx.clone(),
+                                    err,
+                                    &mut check,
+                                    prev_text_size,
+                                )
+                                .await;
                             }
                             _ => {
-                                send_first_event(tx.clone(), Some(err.to_string()), &mut check)
-                                    .await;
+                                send_error_event(
+                                    &req_id,
+                                    tx.clone(),
+                                    err.to_string(),
+                                    &mut check,
+                                    prev_text_size,
+                                )
+                                .await;
                             }
                         }
                         es.close();
                     }
                 }
             }
+            if unexpected_frames > 0 && prev_text_size == 0 {
+                warn!("[{req_id}] Upstream sent no text but {unexpected_frames} frames of unexpected shape, the upstream format may have changed");
+            } else if unexpected_frames > 0 {
+                debug!(
+                    "[{req_id}] Ignored {unexpected_frames} upstream frames of unexpected shape"
+                );
+            }
+            debug!("[{req_id}] Upstream sent {upstream_bytes} bytes");
+            record_diagnostics(&diagnostics, |v| v.upstream_bytes = Some(upstream_bytes));
         });
 
-        let completion_id = generate_completion_id();
-        let created = Utc::now().timestamp();
-
         let first_event = rx.recv().await;
 
         if let Some(ResEvent::First(Some(err))) = first_event {
+            if err == CLOUDFLARE_CHALLENGE_ERROR {
+                return Err(ApiError::new(StatusCode::BAD_GATEWAY, "server_error", err).into());
+            }
             bail!("{err}");
         }
+        Ok(rx)
+    }
+
+    async fn models(&self, _req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let data: Vec<Value> = MODELS.iter().map(|id| self.model_object(id)).collect();
+        let body = json!({
+            "object": "list",
+            "data": data,
+        });
+        let res = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    /// Wait in line for one of the MAX_CONCURRENT_REQUESTS slots, the semaphore serves the
+    /// waiters in arrival order. Rejects the request when the queue is full.
+    async fn acquire_permit(&self, req_id: &str) -> Result<Option<OwnedSemaphorePermit>> {
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let _queued = QueueGuard(&self.queued);
+        if queued >= self.config.max_queue_depth {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service_unavailable",
+                "Too many requests are waiting, please retry later",
+            )
+            .into());
+        }
+        debug!("[{req_id}] Queued behind {queued} requests");
+        Ok(Some(semaphore.clone().acquire_owned().await?))
+    }
+
+    fn track_completion(&self, id: &str) -> CompletionCancel {
+        let (tx, rx) = oneshot::channel();
+        self.completions.lock().unwrap().insert(id.to_string(), tx);
+        CompletionCancel {
+            rx,
+            _guard: CompletionGuard {
+                completions: self.completions.clone(),
+                id: id.to_string(),
+            },
+        }
+    }
+
+    fn cancel_completion(&self, req_id: &str, id: &str) -> Result<AppResponse> {
+        let cancel = self.completions.lock().unwrap().remove(id);
+        if cancel.and_then(|tx| tx.send(()).ok()).is_none() {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "invalid_request_error",
+                format!("No in-progress completion found with id '{id}'"),
+            )
+            .into());
+        }
+        info!("[{req_id}] Cancelled the completion {id}");
+        let body = json!({
+            "id": id,
+            "object": "chat.completion.deleted",
+            "deleted": true,
+        });
+        let res = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn model(&self, id: &str) -> Result<AppResponse> {
+        if !MODELS.contains(&id) {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "model_not_found",
+                format!("The model '{id}' does not exist"),
+            )
+            .into());
+        }
+        let body = self.model_object(id);
+        let res = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.to_string())).boxed())?;
+        Ok(res)
+    }
 
-        if is_stream {
-            let shared = Arc::new((completion_id, created));
-            let stream = ReceiverStream::new(rx);
-            let stream = stream.filter_map(move |v| {
-                let shared = shared.clone();
-                async move {
-                    match v {
-                        ResEvent::Text(text) => {
-                            Some(Ok(create_frame(&shared.0, shared.1, &text, false)))
-                        }
-                        ResEvent::Done => Some(Ok(create_frame(&shared.0, shared.1, "", true))),
-                        _ => None,
-                    }
-                }
-            });
-            let res = Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "text/event-stream")
-                .header("Cache-Control", "no-cache")
-                .header("Connection", "keep-alive")
-                .body(BodyExt::boxed(StreamBody::new(stream)))?;
-            Ok(res)
-        } else {
-            let mut content_parts = vec![];
-            while let Some(event) = rx.recv().await {
-                match event {
-                    ResEvent::Text(text) => {
-                        content_parts.push(text);
-                    }
-                    ResEvent::Done => {
-                        break;
-                    }
-                    _ => {}
+    fn model_object(&self, id: &str) -> Value {
+        json!({
+            "id": id,
+            "object": "model",
+            "created": self.config.models_created,
+            "owned_by": "openai",
+            "permission": [
+                {
+                    "id": "modelperm-001",
+                    "object": "model_permission",
+                    "created": self.config.models_created,
+                    "allow_create_engine": true,
+                    "allow_sampling": true,
+                    "allow_logprobs": true,
+                    "allow_search_indices": false,
+                    "allow_view": true,
+                    "allow_fine_tuning": false,
+                    "organization": "*",
+                    "group": null,
+                    "is_blocking": false
                 }
+            ],
+            "root": id,
+            "parent": null,
+            "context_window": self.config.model_context_window,
+            "max_output_tokens": self.config.model_max_output_tokens,
+            "capabilities": {
+                "streaming": true,
+                "function_calling": false,
+                "vision": false
             }
-            let content = content_parts.join("");
+        })
+    }
 
-            let res = Response::builder()
-                .header("Content-Type", "application/json")
-                .body(Full::new(create_bytes_body(&completion_id, created, &content)).boxed())?;
-            Ok(res)
-        }
+    fn find_blocked_word(&self, text: &str) -> Option<&str> {
+        let text = text.to_lowercase();
+        self.config
+            .blocked_words
+            .iter()
+            .find(|word| contains_word(&text, word))
+            .map(|v| v.as_str())
     }
 
-    async fn models(&self, _req: hyper::Request<Incoming>) -> Result<AppResponse> {
-        let body = json!({
-            "object": "list",
-            "data": [
-                {
-                    "id": "gpt-3.5-turbo",
-                    "object": "model",
-                    "created": 1626777600,
-                    "owned_by": "openai",
-                    "permission": [
-                        {
-                            "id": "modelperm-001",
-                            "object": "model_permission",
-                            "created": 1626777600,
-                            "allow_create_engine": true,
-                            "allow_sampling": true,
-                            "allow_logprobs": true,
-                            "allow_search_indices": false,
-                            "allow_view": true,
-                            "allow_fine_tuning": false,
-                            "organization": "*",
-                            "group": null,
-                            "is_blocking": false
-                        }
-                    ],
-                    "root": "gpt-3.5-turbo",
-                    "parent": null
-                }
-            ]
+    /// Report not ready once shutting down, so load balancers stop sending new requests.
+    fn ready(&self, status: &mut StatusCode) -> Result<AppResponse> {
+        let ready = !self.shutting_down.load(Ordering::Relaxed);
+        if !ready {
+            *status = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        let mut body = json!({
+            "status": if ready { "ready" } else { "shutting_down" },
+            "unexpected_frames": self.unexpected_frames.load(Ordering::Relaxed),
         });
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            let (state, retry_after) = circuit_breaker.state();
+            body["circuit"] = json!({
+                "state": state.as_str(),
+                "retry_after": retry_after.as_secs_f64().ceil() as u64,
+            });
+        }
         let res = Response::builder()
             .header("Content-Type", "application/json")
             .body(Full::new(Bytes::from(body.to_string())).boxed())?;
         Ok(res)
     }
 
-    async fn chat_requirements(&self) -> Result<Requirements> {
-        let oai_device_id = random_id();
-        let res = self
+    async fn playground(&self) -> Result<AppResponse> {
+        let res = Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Full::new(Bytes::from_static(PLAYGROUND_HTML.as_bytes())).boxed())?;
+        Ok(res)
+    }
+
+    async fn chat_requirements(
+        &self,
+        req_id: &str,
+        timeout: Option<Duration>,
+        device_id: Option<&str>,
+        diagnostics: &Option<Arc<Mutex<Diagnostics>>>,
+    ) -> Result<Requirements> {
+        let start = Instant::now();
+        let oai_device_id = match device_id {
+            Some(v) => {
+                debug!("[{req_id}] Using the device id {v}");
+                v.to_string()
+            }
+            None => random_id(),
+        };
+        let mut builder = self
             .client
-            .post(CHAT_REQUIREMENTS_URL)
-            .headers(common_headers())
+            .post(format!(
+                "{}{CHAT_REQUIREMENTS_PATH}",
+                self.config.upstream_base_url
+            ))
+            .headers(self.headers.clone())
             .header("oai-device-id", oai_device_id.clone())
-            .body("{}")
-            .send()
-            .await?;
-        let data: Value = res.json().await?;
+            .body("{}");
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        let res = builder.send().await?;
+        let status = res.status();
+        let text = res.text().await?;
+        record_diagnostics(diagnostics, |v| {
+            v.upstream_status = Some(status.as_u16());
+            v.upstream_body = Some(snippet(&text));
+            v.requirements_ms = Some(start.elapsed().as_millis());
+        });
+        if is_cloudflare_challenge(status, &text) {
+            warn!(
+                "[{req_id}] Chat requirements returned a Cloudflare challenge, {}",
+                snippet(&text)
+            );
+            return Err(ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "server_error",
+                CLOUDFLARE_CHALLENGE_ERROR,
+            )
+            .into());
+        }
+        let data: Value = serde_json::from_str(&text)
+            .map_err(|err| anyhow!("Invalid response code {status}, {err}, {}", snippet(&text)))?;
+        debug!("[{req_id}] chat requirements: {data}");
         if let (Some(token), Some((seed, difficulty))) = (
             data["token"].as_str(),
             data["proofofwork"].as_object().and_then(|v| {
@@ -477,6 +2151,10 @@ impl Server {
                 }
             }),
         ) {
+            record_diagnostics(diagnostics, |v| {
+                v.seed = Some(seed.to_string());
+                v.difficulty = Some(difficulty.to_string());
+            });
             Ok(Requirements {
                 oai_device_id,
                 token: token.to_string(),
@@ -489,11 +2167,152 @@ impl Server {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum ResEvent {
     First(Option<String>),
     Text(String),
-    Done,
+    /// The whole answer so far, replacing the text before it when the upstream rewrote it. Only
+    /// sent for `X-Final-Only`, whose text is held back until the end.
+    Snapshot(String),
+    ToolCalls(Value),
+    /// The `moderation_response` of the upstream.
+    Moderation(Value),
+    /// The upstream failed after some content was sent, followed by `Done("length")`.
+    Interrupted(String),
+    Done(&'static str),
+    Error(String),
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    message: String,
+    debug: Option<Value>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            kind,
+            message: message.into(),
+            debug: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Clone)]
+struct CompletionOptions {
+    upstream_timeout: Option<Duration>,
+    history_disabled: bool,
+    strip_markdown: bool,
+    system_prompt: Option<String>,
+    final_only: bool,
+    /// Taken from `OAI_DEVICE_IDS`, the upstream requests of a completion share it.
+    device_id: Option<String>,
+    /// Collected only for `X-Debug: 1` requests.
+    diagnostics: Option<Arc<Mutex<Diagnostics>>>,
+    /// From `REQUEST_DEADLINE`, when the answer is due whatever its state.
+    deadline: Option<tokio::time::Instant>,
+    /// Read from the upstream for the request, across its retries, reported in `X-Upstream-Bytes`.
+    upstream_bytes: Arc<AtomicUsize>,
+}
+
+impl CompletionOptions {
+    /// Add the collected diagnostics to the error response, if requested.
+    fn attach_diagnostics(&self, err: anyhow::Error) -> anyhow::Error {
+        let Some(diagnostics) = &self.diagnostics else {
+            return err;
+        };
+        let mut err = match err.downcast::<ApiError>() {
+            Ok(err) => err,
+            Err(err) => ApiError::new(StatusCode::OK, "invalid_request_error", err.to_string()),
+        };
+        err.debug = Some(diagnostics.lock().unwrap().to_value());
+        err.into()
+    }
+}
+
+/// What happened upstream while serving a request. The tokens are deliberately left out.
+#[derive(Debug)]
+struct Diagnostics {
+    start: Instant,
+    upstream_status: Option<u16>,
+    upstream_body: Option<String>,
+    seed: Option<String>,
+    difficulty: Option<String>,
+    proof_iterations: Option<usize>,
+    upstream_bytes: Option<usize>,
+    requirements_ms: Option<u128>,
+    proof_ms: Option<u128>,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            upstream_status: None,
+            upstream_body: None,
+            seed: None,
+            difficulty: None,
+            proof_iterations: None,
+            upstream_bytes: None,
+            requirements_ms: None,
+            proof_ms: None,
+        }
+    }
+}
+
+impl Diagnostics {
+    fn to_value(&self) -> Value {
+        json!({
+            "upstream_status": self.upstream_status,
+            "upstream_body": self.upstream_body,
+            "seed": self.seed,
+            "difficulty": self.difficulty,
+            "proof_iterations": self.proof_iterations,
+            "upstream_bytes": self.upstream_bytes,
+            "timing": {
+                "requirements_ms": self.requirements_ms,
+                "proof_ms": self.proof_ms,
+                "total_ms": self.start.elapsed().as_millis(),
+            },
+        })
+    }
+}
+
+fn record_diagnostics(
+    diagnostics: &Option<Arc<Mutex<Diagnostics>>>,
+    f: impl FnOnce(&mut Diagnostics),
+) {
+    if let Some(diagnostics) = diagnostics {
+        f(&mut diagnostics.lock().unwrap());
+    }
+}
+
+#[derive(Clone)]
+struct CompletionMeta {
+    id: String,
+    created: i64,
+    system_fingerprint: Option<String>,
+    logprobs: bool,
+    seed: Option<i64>,
+    metadata: Option<Value>,
+    /// The canned answer served while the upstream is failing.
+    fallback: bool,
+    /// Set once `REQUEST_DEADLINE` cut the answer short.
+    deadline_hit: Arc<AtomicBool>,
+    /// With diagnostics, the chunks carry the time elapsed since the request in `x_timing`.
+    timing: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -504,6 +2323,93 @@ struct Requirements {
     difficulty: String,
 }
 
+fn conversation_eventsource(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    requirements: &Requirements,
+    proof_token: Option<&str>,
+    req_body: &Value,
+    max_frame_size: usize,
+) -> EventSource {
+    let mut builder = client
+        .post(url)
+        .headers(headers.clone())
+        .header("oai-device-id", &requirements.oai_device_id)
+        .header(
+            "openai-sentinel-chat-requirements-token",
+            &requirements.token,
+        );
+    if let Some(proof_token) = proof_token {
+        builder = builder.header("openai-sentinel-proof-token", proof_token);
+    }
+    EventSource::new(builder.json(req_body), max_frame_size)
+}
+
+async fn solve_proof_token(
+    req_id: &str,
+    format: &ProofFormat,
+    requirements: &Requirements,
+    threads: usize,
+    cancel: Arc<AtomicBool>,
+    diagnostics: &Option<Arc<Mutex<Diagnostics>>>,
+) -> Result<String> {
+    let start = Instant::now();
+    let req_id = req_id.to_string();
+    let seed = requirements.seed.clone();
+    let difficulty = requirements.difficulty.clone();
+    let format = format.clone();
+    let (token, iterations) = tokio::task::spawn_blocking(move || {
+        calculate_proof_token(&req_id, &format, &seed, &difficulty, threads, &cancel)
+    })
+    .await??;
+    record_diagnostics(diagnostics, |v| {
+        v.proof_iterations = Some(iterations);
+        v.proof_ms = Some(start.elapsed().as_millis());
+    });
+    Ok(token)
+}
+
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+struct CancelOnDrop(Arc<AtomicBool>);
+
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Receives the cancellation of a completion, which stays cancellable until this is dropped.
+struct CompletionCancel {
+    rx: oneshot::Receiver<()>,
+    _guard: CompletionGuard,
+}
+
+struct CompletionGuard {
+    completions: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+    id: String,
+}
+
+impl Drop for CompletionGuard {
+    fn drop(&mut self) {
+        self.completions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
 async fn send_first_event(tx: Sender<ResEvent>, data: Option<String>, check: &mut bool) {
     if *check {
         let _ = tx.send(ResEvent::First(data)).await;
@@ -511,13 +2417,45 @@ async fn send_first_event(tx: Sender<ResEvent>, data: Option<String>, check: &mu
     }
 }
 
+fn empty_content_error(req_id: &str, empty_messages: usize) -> String {
+    if empty_messages == 0 {
+        return EMPTY_CONTENT_ERROR.to_string();
+    }
+    warn!("[{req_id}] Upstream only sent {empty_messages} empty assistant messages");
+    format!("{EMPTY_CONTENT_ERROR}, only {empty_messages} empty assistant messages")
+}
+
+/// Report an upstream error, keeping the content already generated if the stream has started.
+async fn send_error_event(
+    req_id: &str,
+    tx: Sender<ResEvent>,
+    err: String,
+    check: &mut bool,
+    prev_text_size: usize,
+) {
+    if *check {
+        send_first_event(tx, Some(err), check).await;
+    } else if prev_text_size > 0 {
+        warn!("[{req_id}] Upstream stream interrupted, returning partial content, {err}");
+        let _ = tx.send(ResEvent::Interrupted(err)).await;
+        let _ = tx.send(ResEvent::Done("length")).await;
+    } else {
+        let _ = tx.send(ResEvent::Error(err)).await;
+    }
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
         .expect("Failed to install CTRL+C signal handler")
 }
 
-fn generate_completion_id() -> String {
+/// A short id to correlate the log lines of one request.
+fn generate_request_id() -> String {
+    random_id()[..8].to_string()
+}
+
+fn generate_completion_id(prefix: &str) -> String {
     let mut rng = thread_rng();
 
     let id_charset: Vec<char> = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789"
@@ -528,10 +2466,10 @@ fn generate_completion_id() -> String {
         .map(|_| *id_charset.choose(&mut rng).unwrap())
         .collect();
 
-    format!("chatcmpl-{}", random_id)
+    format!("{prefix}{random_id}")
 }
 
-fn common_headers() -> HeaderMap {
+fn common_headers(config: &Config) -> Result<HeaderMap> {
     let mut headers = HeaderMap::new();
 
     headers.insert("accept", HeaderValue::from_static("*/*"));
@@ -565,7 +2503,18 @@ fn common_headers() -> HeaderMap {
     headers.insert("sec-fetch-site", HeaderValue::from_static("same-origin"));
     headers.insert("user-agent", HeaderValue::from_static(USER_AGENT));
 
-    headers
+    let overrides = [
+        ("priority", &config.upstream_priority),
+        ("sec-fetch-site", &config.upstream_sec_fetch_site),
+        ("sec-fetch-mode", &config.upstream_sec_fetch_mode),
+    ];
+    for (name, value) in overrides {
+        if let Some(value) = value {
+            headers.insert(name, HeaderValue::from_str(value)?);
+        }
+    }
+
+    Ok(headers)
 }
 
 fn set_cors_header(res: &mut AppResponse) {
@@ -579,13 +2528,24 @@ fn set_cors_header(res: &mut AppResponse) {
     );
     res.headers_mut().insert(
         hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
-        hyper::header::HeaderValue::from_static("Content-Type,Authorization"),
+        hyper::header::HeaderValue::from_static("Content-Type,Authorization,Api-Key"),
     );
 }
 
-fn create_frame(id: &str, created: i64, content: &str, done: bool) -> Frame<Bytes> {
-    let (delta, finish_reason) = if done {
-        (json!({}), "stop".into())
+fn create_frame(meta: &CompletionMeta, content: &str, finish_reason: Option<&str>) -> Frame<Bytes> {
+    let value = create_chunk(meta, content, finish_reason);
+    let output = if finish_reason.is_some() {
+        format!("data: {value}\n\ndata: [DONE]\n\n")
+    } else {
+        format!("data: {value}\n\n")
+    };
+    Frame::data(Bytes::from(output))
+}
+
+fn create_chunk(meta: &CompletionMeta, content: &str, finish_reason: Option<&str>) -> Value {
+    let done = finish_reason.is_some();
+    let (delta, finish_reason) = if let Some(finish_reason) = finish_reason {
+        (json!({}), finish_reason.into())
     } else {
         let delta = if content.is_empty() {
             json!({ "role": "assistant", "content": content })
@@ -595,9 +2555,9 @@ fn create_frame(id: &str, created: i64, content: &str, done: bool) -> Frame<Byte
         (delta, Value::Null)
     };
     let mut value = json!({
-        "id": id,
+        "id": meta.id,
         "object": "chat.completion.chunk",
-        "created": created,
+        "created": meta.created,
         "model": "gpt-3.5-turbo",
         "choices": [
             {
@@ -607,24 +2567,82 @@ fn create_frame(id: &str, created: i64, content: &str, done: bool) -> Frame<Byte
             },
         ],
     });
-    let output = if done {
+    if let Some(system_fingerprint) = &meta.system_fingerprint {
+        value["system_fingerprint"] = system_fingerprint.as_str().into();
+    }
+    if meta.logprobs {
+        value["choices"][0]["logprobs"] = Value::Null;
+    }
+    if let Some(seed) = meta.seed {
+        value["seed"] = seed.into();
+    }
+    if let Some(started) = meta.timing {
+        value["x_timing"] = json!({ "elapsed_ms": started.elapsed().as_millis() as u64 });
+    }
+    if done {
         value["usage"] = json!({
             "prompt_tokens": 0,
             "completion_tokens": 0,
             "total_tokens": 0,
         });
-        format!("data: {value}\n\ndata: [DONE]\n\n")
+    }
+    value
+}
+
+/// The role delta starting a stream, preceded like Azure by the prompt filter results.
+fn role_frame(meta: &CompletionMeta, prompt_filter: bool) -> Frame<Bytes> {
+    let value = create_chunk(meta, "", None);
+    let output = if prompt_filter {
+        let filter = create_prompt_filter_chunk(meta, None);
+        format!("data: {filter}\n\ndata: {value}\n\n")
     } else {
         format!("data: {value}\n\n")
     };
     Frame::data(Bytes::from(output))
 }
 
-fn create_bytes_body(id: &str, created: i64, content: &str) -> Bytes {
-    let res_body = json!({
-        "id": id,
+fn create_tool_calls_chunk(meta: &CompletionMeta, tool_calls: Value) -> Value {
+    let mut value = create_chunk(meta, "", None);
+    value["choices"][0]["delta"] = json!({ "tool_calls": tool_calls });
+    value
+}
+
+fn create_prompt_filter_chunk(meta: &CompletionMeta, moderation: Option<&Value>) -> Value {
+    let mut value = create_chunk(meta, "", None);
+    value["choices"] = json!([]);
+    value["prompt_filter_results"] = prompt_filter_results(moderation);
+    value
+}
+
+/// Describe the moderation of the upstream like the Azure content filter. The upstream gives no
+/// categories, so a flagged prompt is reported as such rather than under a made-up category.
+fn prompt_filter_results(moderation: Option<&Value>) -> Value {
+    let flagged = moderation.is_some_and(|v| v["flagged"].as_bool() == Some(true));
+    let content_filter_results = if flagged {
+        let blocked = moderation.is_some_and(|v| v["blocked"].as_bool() == Some(true));
+        json!({ "moderation": { "filtered": blocked, "flagged": true } })
+    } else {
+        let safe = json!({ "filtered": false, "severity": "safe" });
+        json!({
+            "hate": safe,
+            "self_harm": safe,
+            "sexual": safe,
+            "violence": safe,
+        })
+    };
+    json!([{ "prompt_index": 0, "content_filter_results": content_filter_results }])
+}
+
+fn create_completion(
+    meta: &CompletionMeta,
+    content: &str,
+    tool_calls: Option<Value>,
+    finish_reason: &str,
+) -> Value {
+    let mut res_body = json!({
+        "id": meta.id,
         "object": "chat.completion",
-        "created": created,
+        "created": meta.created,
         "model": "gpt-3.5-turbo",
         "choices": [
             {
@@ -633,7 +2651,7 @@ fn create_bytes_body(id: &str, created: i64, content: &str) -> Bytes {
                     "role": "assistant",
                     "content": content,
                 },
-                "finish_reason": "stop",
+                "finish_reason": finish_reason,
             },
         ],
         "usage": {
@@ -642,17 +2660,85 @@ fn create_bytes_body(id: &str, created: i64, content: &str) -> Bytes {
             "total_tokens": 0,
         },
     });
-    Bytes::from(res_body.to_string())
+    if let Some(system_fingerprint) = &meta.system_fingerprint {
+        res_body["system_fingerprint"] = system_fingerprint.as_str().into();
+    }
+    if meta.logprobs {
+        res_body["choices"][0]["logprobs"] = Value::Null;
+    }
+    if let Some(tool_calls) = tool_calls {
+        if content.is_empty() {
+            res_body["choices"][0]["message"]["content"] = Value::Null;
+        }
+        res_body["choices"][0]["message"]["tool_calls"] = tool_calls;
+    }
+    // A refused answer also fills the newer `refusal` field, content is kept for older clients.
+    if finish_reason == "content_filter" && !content.is_empty() {
+        res_body["choices"][0]["message"]["refusal"] = content.into();
+    }
+    if let Some(seed) = meta.seed {
+        res_body["seed"] = seed.into();
+    }
+    if let Some(metadata) = &meta.metadata {
+        res_body["metadata"] = metadata.clone();
+    }
+    res_body
+}
+
+/// The usage record pushed to `$USAGE_WEBHOOK`, the token counts match the response usage.
+fn usage_payload(
+    req_id: &str,
+    completion_id: &str,
+    user: Option<&str>,
+    started: Instant,
+    outcome: std::result::Result<usize, String>,
+) -> Value {
+    json!({
+        "request_id": req_id,
+        "id": completion_id,
+        "model": MODELS[0],
+        "user": user,
+        "usage": {
+            "prompt_tokens": 0,
+            "completion_tokens": 0,
+            "total_tokens": 0,
+        },
+        "completion_chars": outcome.as_ref().ok(),
+        "latency_ms": started.elapsed().as_millis(),
+        "success": outcome.is_ok(),
+        "error": outcome.err(),
+    })
 }
 
-fn create_error_response<T: std::fmt::Display>(err: T) -> AppResponse {
-    let data = json!({
+fn create_error_frame(message: &str, kind: &str) -> Frame<Bytes> {
+    let value = create_error_value(message, kind);
+    Frame::data(Bytes::from(format!("data: {value}\n\ndata: [DONE]\n\n")))
+}
+
+fn create_error_value(message: &str, kind: &str) -> Value {
+    json!({
+        "error": {
+            "message": message,
+            "type": kind,
+        },
+    })
+}
+
+fn create_error_response<T: std::fmt::Display>(
+    err: T,
+    kind: &str,
+    debug: Option<&Value>,
+) -> AppResponse {
+    let mut data = json!({
         "status": false,
         "error": {
             "message": err.to_string(),
-            "type": "invalid_request_error",
+            "type": kind,
         },
     });
+    if let Some(debug) = debug {
+        data["debug"] = debug.clone();
+    }
     Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
@@ -664,34 +2750,435 @@ fn random_id() -> String {
     Uuid::new_v4().to_string()
 }
 
-fn calculate_proof_token(seed: &str, diff: &str) -> String {
-    let now = Utc::now();
-    let datetime = now.format("%a %b %d %Y %H:%M:%S GMT%z (Coordinated Universal Time)");
-
+/// Try the nonces `offset`, `offset + step`, ... until one meets the difficulty, or another
+/// thread has found one, or the request is cancelled.
+fn search_proof(
+    format: &ProofFormat,
+    seed: &str,
+    datetime: &str,
+    diff: &str,
+    nonces: impl Iterator<Item = usize>,
+    found: &AtomicBool,
+    cancel: &AtomicBool,
+) -> Option<(usize, String)> {
     let diff_len = diff.len() / 2;
-    let mut hasher = Sha3_512::new();
 
-    for i in 0..100000 {
-        let value = format!(
-            r#"[{},"{datetime}",4294705152,{},"{USER_AGENT}"]"#,
-            *PROOF_V1, i
-        );
-        let base = STANDARD.encode(value);
-        hasher.update(format!("{}{}", seed, base).as_bytes());
-        let hash = hasher.finalize_reset();
+    for (n, i) in nonces.enumerate() {
+        if n % POW_CANCEL_CHECK_INTERVAL == 0
+            && (found.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed))
+        {
+            return None;
+        }
+        let base = STANDARD.encode(proof_payload(format, datetime, i));
+        let hash = format
+            .algorithm
+            .hash(format!("{}{}", seed, base).as_bytes());
         let hash_hex = hex_encode(&hash[..diff_len]);
 
         if hash_hex.as_str() <= diff {
-            return format!("gAAAAAB{}", base);
+            found.store(true, Ordering::Relaxed);
+            return Some((i, base));
+        }
+    }
+    None
+}
+
+/// Build the browser fingerprint that is hashed with the seed, as currently understood:
+/// `[screen size, Date().toString(), jsHeapSizeLimit, nonce, navigator.userAgent]`, unless
+/// `POW_PAYLOAD_TEMPLATE` describes another one.
+fn proof_payload(format: &ProofFormat, datetime: &str, nonce: usize) -> String {
+    match &format.payload_template {
+        Some(template) => template
+            .replace("{screen}", &PROOF_V1.to_string())
+            .replace("{datetime}", datetime)
+            .replace("{heap_size}", &POW_HEAP_SIZE_LIMIT.to_string())
+            .replace("{nonce}", &nonce.to_string())
+            .replace("{user_agent}", USER_AGENT),
+        None => json!([*PROOF_V1, datetime, POW_HEAP_SIZE_LIMIT, nonce, USER_AGENT]).to_string(),
+    }
+}
+
+fn calculate_proof_token(
+    req_id: &str,
+    format: &ProofFormat,
+    seed: &str,
+    diff: &str,
+    threads: usize,
+    cancel: &AtomicBool,
+) -> Result<(String, usize)> {
+    let start = Instant::now();
+    let now = Utc::now();
+    let datetime = now
+        .format("%a %b %d %Y %H:%M:%S GMT%z (Coordinated Universal Time)")
+        .to_string();
+
+    let found = AtomicBool::new(false);
+    let solved = if threads <= 1 {
+        let nonces = 0..POW_MAX_ITERATIONS;
+        search_proof(format, seed, &datetime, diff, nonces, &found, cancel)
+    } else {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|offset| {
+                    let (datetime, found) = (&datetime, &found);
+                    let nonces = (offset..POW_MAX_ITERATIONS).step_by(threads);
+                    scope.spawn(move || {
+                        search_proof(format, seed, datetime, diff, nonces, found, cancel)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|v| v.join().ok().flatten())
+                .min_by_key(|(i, _)| *i)
+        })
+    };
+
+    if let Some((i, base)) = solved {
+        debug!(
+            "[{req_id}] proof of work solved in {} iterations, {}ms, difficulty {diff}, {threads} threads, {}",
+            i + 1,
+            start.elapsed().as_millis(),
+            format.algorithm
+        );
+        return Ok((format!("{POW_TOKEN_PREFIX}{base}"), i + 1));
+    }
+    if cancel.load(Ordering::Relaxed) {
+        bail!(
+            "Proof of work was cancelled after {}ms",
+            start.elapsed().as_millis()
+        );
+    }
+
+    warn!(
+        "[{req_id}] proof of work unsolved after {} iterations, {}ms, difficulty {diff}",
+        POW_MAX_ITERATIONS,
+        start.elapsed().as_millis()
+    );
+
+    Ok((
+        format!(
+            "{POW_FALLBACK_TOKEN_PREFIX}{}",
+            STANDARD.encode(format!("\"{}\"", seed))
+        ),
+        POW_MAX_ITERATIONS,
+    ))
+}
+
+/// Check that a proof of work is found for a known seed and difficulty and that it holds up,
+/// catching regressions in the hashing or the encoding before real traffic does.
+fn proof_self_test(format: &ProofFormat, threads: usize) -> bool {
+    let cancel = AtomicBool::new(false);
+    let start = Instant::now();
+    let token = calculate_proof_token(
+        "selftest",
+        format,
+        SELFTEST_SEED,
+        SELFTEST_DIFFICULTY,
+        threads,
+        &cancel,
+    );
+    match token {
+        Ok((token, iterations))
+            if !token.starts_with(POW_FALLBACK_TOKEN_PREFIX)
+                && meets_difficulty(
+                    format,
+                    SELFTEST_SEED,
+                    &token[POW_TOKEN_PREFIX.len()..],
+                    SELFTEST_DIFFICULTY,
+                ) =>
+        {
+            info!(
+                "Self-test passed, proof of work solved in {iterations} iterations, {}ms",
+                start.elapsed().as_millis()
+            );
+            true
+        }
+        Ok(_) => {
+            warn!(
+                "SELF-TEST FAILED: proof of work found no valid solution for difficulty {SELFTEST_DIFFICULTY} within {POW_MAX_ITERATIONS} iterations, the upstream will likely reject conversations"
+            );
+            false
+        }
+        Err(err) => {
+            warn!("SELF-TEST FAILED: proof of work errored, {err}");
+            false
+        }
+    }
+}
+
+fn meets_difficulty(format: &ProofFormat, seed: &str, base: &str, diff: &str) -> bool {
+    let hash = format.algorithm.hash(format!("{seed}{base}").as_bytes());
+    hex_encode(&hash[..diff.len() / 2]).as_str() <= diff
+}
+
+/// Map the `tool_calls` or legacy `function_call` of an upstream message to the OpenAI shape.
+fn parse_tool_calls(message: &Value) -> Option<Value> {
+    let calls: Vec<&Value> = match (
+        message["tool_calls"].as_array(),
+        message.get("function_call"),
+    ) {
+        (Some(calls), _) if !calls.is_empty() => calls.iter().collect(),
+        (_, Some(call)) if call.is_object() => vec![call],
+        _ => return None,
+    };
+    let calls: Vec<Value> = calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| {
+            let function = if call["function"].is_object() {
+                &call["function"]
+            } else {
+                call
+            };
+            let arguments = match &function["arguments"] {
+                Value::String(v) => v.clone(),
+                Value::Null => String::new(),
+                v => v.to_string(),
+            };
+            json!({
+                "index": index,
+                "id": call["id"].as_str().map(|v| v.to_string()).unwrap_or_else(|| format!("call_{}", random_id())),
+                "type": "function",
+                "function": {
+                    "name": function["name"],
+                    "arguments": arguments,
+                },
+            })
+        })
+        .collect();
+    Some(calls.into())
+}
+
+/// Detect the answers the upstream flags as refused or blocked by moderation.
+fn is_refusal(data: &Value) -> bool {
+    data["moderation_response"]["blocked"].as_bool() == Some(true)
+        || data["message"]["metadata"]["refusal"]
+            .as_str()
+            .is_some_and(|v| !v.is_empty())
+        || data["message"]["metadata"]["refusal"].as_bool() == Some(true)
+}
+
+/// A message without a role, or an assistant message that is neither text nor tool calls,
+/// whose content would be dropped.
+fn is_unexpected_message(message: &Value) -> bool {
+    if !message.is_object() {
+        return false;
+    }
+    match message["author"]["role"].as_str() {
+        Some("assistant") => parse_tool_calls(message).is_none(),
+        Some(_) => false,
+        None => true,
+    }
+}
+
+/// Keep the shape of an upstream frame for the logs, replacing the text by its length.
+/// Short strings without spaces, like roles and content types, are kept.
+fn redact_frame(value: &Value) -> Value {
+    match value {
+        Value::String(v) if v.len() > 40 || v.contains(char::is_whitespace) => {
+            format!("<{} chars>", v.chars().count()).into()
+        }
+        Value::Array(arr) => arr.iter().map(redact_frame).collect(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(k, v)| (k.clone(), redact_frame(v)))
+            .collect(),
+        v => v.clone(),
+    }
+}
+
+/// Detect the "Just a moment..." interstitial Cloudflare serves instead of the API.
+fn is_cloudflare_challenge(status: StatusCode, body: &str) -> bool {
+    (status == StatusCode::FORBIDDEN || status == StatusCode::SERVICE_UNAVAILABLE)
+        && CLOUDFLARE_CHALLENGE_MARKERS
+            .iter()
+            .any(|v| body.contains(v))
+}
+
+/// Shorten an upstream body for the logs.
+fn snippet(text: &str) -> String {
+    let mut snippet: String = text.chars().take(200).collect();
+    if snippet.len() < text.len() {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+/// Decompress a gzip or deflate encoded request body, limiting the decompressed size.
+fn decode_body(encoding: Option<&str>, body: Bytes, max_size: usize) -> Result<Bytes> {
+    let mut reader: Box<dyn Read> = match encoding {
+        None | Some("identity") => return Ok(body),
+        Some("gzip") | Some("x-gzip") => Box::new(GzDecoder::new(body.as_ref())),
+        Some("deflate") => Box::new(ZlibDecoder::new(body.as_ref())),
+        Some(encoding) => {
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "invalid_request_error",
+                format!("Unsupported Content-Encoding '{encoding}', expected gzip or deflate"),
+            )
+            .into())
         }
+    };
+    let mut output = vec![];
+    reader
+        .by_ref()
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|err| {
+            anyhow!(
+                "Invalid {} request body, {err}",
+                encoding.unwrap_or_default()
+            )
+        })?;
+    if output.len() > max_size {
+        return Err(body_too_large(max_size).into());
     }
+    Ok(output.into())
+}
 
-    format!(
-        "gAAAAABwQ8Lk5FbGpA2NcR9dShT6gYjU7VxZ4D{}",
-        STANDARD.encode(format!("\"{}\"", seed))
+fn body_too_large(max_size: usize) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "invalid_request_error",
+        format!("Request body exceeds {max_size} bytes"),
     )
 }
 
+/// Accept `application/json` and `application/*+json`, with any parameters such as the charset.
+fn is_json_content_type(value: &str) -> bool {
+    let mime = value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+/// Look up a request parameter by its snake_case name, falling back to the camelCase spelling.
+fn get_param<'a>(body: &'a Value, name: &str) -> &'a Value {
+    let value = &body[name];
+    if !value.is_null() {
+        return value;
+    }
+    let mut camel_case = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            camel_case.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            camel_case.push(c);
+        }
+    }
+    &body[camel_case.as_str()]
+}
+
+/// Look up a boolean request parameter, tolerating buggy clients that send `"true"` or `1`.
+fn get_bool_param(body: &Value, name: &str) -> Option<bool> {
+    match get_param(body, name) {
+        Value::Bool(v) => Some(*v),
+        Value::String(v) => parse_bool(v.trim()),
+        Value::Number(v) => match v.as_u64() {
+            Some(1) => Some(true),
+            Some(0) => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Check whether `word` occurs in `text` without being part of a longer word.
+fn contains_word(text: &str, word: &str) -> bool {
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Describe the `tool_calls`, or legacy `function_call`, of an assistant message as text.
+fn describe_tool_calls(message: &Value) -> String {
+    let calls: Vec<&Value> = match (
+        message["tool_calls"].as_array(),
+        message.get("function_call"),
+    ) {
+        (Some(calls), _) => calls.iter().map(|v| &v["function"]).collect(),
+        (None, Some(call)) => vec![call],
+        (None, None) => vec![],
+    };
+    calls
+        .into_iter()
+        .filter_map(|v| {
+            let name = v["name"].as_str()?;
+            let arguments = v["arguments"].as_str().unwrap_or("{}");
+            Some(format!("Called {name} with {arguments}"))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parse an answer, possibly wrapped in a markdown code block, and check it against the schema.
+fn check_json_answer(text: &str, schema: &Value) -> std::result::Result<String, Vec<String>> {
+    let text = text.trim();
+    let text = match text.strip_prefix("```") {
+        Some(v) => {
+            let v = v.trim_start_matches("json");
+            v.strip_suffix("```").unwrap_or(v).trim()
+        }
+        None => text,
+    };
+    let value: Value =
+        serde_json::from_str(text).map_err(|err| vec![format!("invalid JSON, {err}")])?;
+    let errors = schema::validate_json(&value, schema);
+    if errors.is_empty() {
+        Ok(text.to_string())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Remove the base path from the request path, `None` when the path lies outside of it.
+fn strip_base_path(base_path: &str, uri: &Uri) -> Option<Uri> {
+    if base_path.is_empty() {
+        return Some(uri.clone());
+    }
+    let path = uri.path().strip_prefix(base_path)?;
+    let path = match path {
+        "" => "/",
+        v if v.starts_with('/') => v,
+        _ => return None,
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    path_and_query.parse().ok()
+}
+
+fn role_label(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Compare a presented key with a configured one in constant time. The digests have a fixed
+/// length, so neither the position of the first difference nor the key length shows.
+fn key_matches(presented: &[u8], expected: &[u8]) -> bool {
+    let (presented, expected) = (digest(&SHA256, presented), digest(&SHA256, expected));
+    let diff = presented
+        .as_ref()
+        .iter()
+        .zip(expected.as_ref())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    diff == 0
+}
+
 fn hex_encode(bytes: &[u8]) -> String {
     bytes
         .iter()
@@ -0,0 +1,45 @@
+use regex::Regex;
+
+lazy_static::lazy_static! {
+    static ref IMAGE_RE: Regex = Regex::new(r"!\[([^\]]*)\]\([^)]*\)").unwrap();
+    static ref LINK_RE: Regex = Regex::new(r"\[([^\]]+)\]\([^)]*\)").unwrap();
+    static ref HEADING_RE: Regex = Regex::new(r"^\s{0,3}#{1,6}\s+").unwrap();
+    static ref BLOCKQUOTE_RE: Regex = Regex::new(r"^\s{0,3}>\s?").unwrap();
+    static ref BULLET_RE: Regex = Regex::new(r"^(\s*)[*+]\s+").unwrap();
+    static ref RULE_RE: Regex = Regex::new(r"^\s{0,3}([-*_]\s*){3,}$").unwrap();
+    static ref STRONG_RE: Regex = Regex::new(r"(\*\*|__|~~)(\S(?:.*?\S)?)(\*\*|__|~~)").unwrap();
+    static ref EMPHASIS_RE: Regex = Regex::new(r"(^|[^\w*])[*_](\S(?:.*?\S)?)[*_]($|[^\w*])").unwrap();
+    static ref CODE_RE: Regex = Regex::new(r"`([^`]*)`").unwrap();
+}
+
+/// Convert markdown into plain text one line at a time, remembering whether a code fence is open.
+#[derive(Debug, Default)]
+pub struct MarkdownStripper {
+    in_code_block: bool,
+}
+
+impl MarkdownStripper {
+    /// Strip a single line (without its trailing newline), returning `None` for lines that
+    /// only carry markup, such as code fences.
+    pub fn strip_line(&mut self, line: &str) -> Option<String> {
+        if line.trim_start().starts_with("```") {
+            self.in_code_block = !self.in_code_block;
+            return None;
+        }
+        if self.in_code_block {
+            return Some(line.to_string());
+        }
+        if RULE_RE.is_match(line) {
+            return Some(String::new());
+        }
+        let line = HEADING_RE.replace(line, "");
+        let line = BLOCKQUOTE_RE.replace(&line, "");
+        let line = BULLET_RE.replace(&line, "$1- ");
+        let line = IMAGE_RE.replace_all(&line, "$1");
+        let line = LINK_RE.replace_all(&line, "$1");
+        let line = CODE_RE.replace_all(&line, "$1");
+        let line = STRONG_RE.replace_all(&line, "$2");
+        let line = EMPHASIS_RE.replace_all(&line, "$1$2$3");
+        Some(line.into_owned())
+    }
+}
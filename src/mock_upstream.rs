@@ -0,0 +1,231 @@
+//! A stand-in for the ChatGPT backend, serving the chat requirements and conversation endpoints
+//! from canned responses so the server can be tested end to end without network access.
+
+use crate::{CHAT_REQUIREMENTS_PATH, CONVERSATION_PATH};
+
+use bytes::Bytes;
+use http::{HeaderMap, Response, StatusCode, Version};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{body::Frame, service::service_fn};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use serde_json::{json, Value};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+type Handler = dyn Fn(&MockRequest) -> MockResponse + Send + Sync;
+
+pub struct MockUpstream {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub path: String,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Value,
+}
+
+pub struct MockResponse {
+    status: StatusCode,
+    content_type: &'static str,
+    chunks: Vec<Chunk>,
+}
+
+enum Chunk {
+    Data(String),
+    Delay(Duration),
+    /// Cut the connection without finishing the body.
+    Break,
+}
+
+impl MockUpstream {
+    /// Serve the requirements with an easy proof of work and answer conversations with `handler`.
+    pub async fn start(
+        handler: impl Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    ) -> Self {
+        Self::start_with(move |req| match req.path.as_str() {
+            CHAT_REQUIREMENTS_PATH => MockResponse::requirements(),
+            _ => handler(req),
+        })
+        .await
+    }
+
+    /// Answer every conversation with the given snapshots of the assistant message.
+    pub async fn answer(parts: &'static [&'static str]) -> Self {
+        Self::start(move |_| MockResponse::answer(parts)).await
+    }
+
+    /// Answer both endpoints with `handler`.
+    pub async fn start_with(
+        handler: impl Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests: Arc<Mutex<Vec<MockRequest>>> = Default::default();
+        let handler: Arc<Handler> = Arc::new(handler);
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                // Send each chunk at once, as a real upstream does, not held back by Nagle.
+                let _ = stream.set_nodelay(true);
+                let (handler, recorded) = (handler.clone(), recorded.clone());
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                        let (handler, recorded) = (handler.clone(), recorded.clone());
+                        async move {
+                            let path = req.uri().path().to_string();
+                            let version = req.version();
+                            let headers = req.headers().clone();
+                            let body = req.into_body().collect().await?.to_bytes();
+                            let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                            let req = MockRequest {
+                                path,
+                                version,
+                                headers,
+                                body,
+                            };
+                            recorded.lock().unwrap().push(req.clone());
+                            Ok::<_, hyper::Error>(handler(&req).into_response())
+                        }
+                    });
+                    let _ = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The requests received so far, in arrival order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The conversation requests received so far, in arrival order.
+    pub fn conversations(&self) -> Vec<MockRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|v| v.path == CONVERSATION_PATH)
+            .collect()
+    }
+}
+
+impl MockResponse {
+    pub fn new(status: StatusCode, content_type: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type,
+            chunks: vec![Chunk::Data(body.into())],
+        }
+    }
+
+    pub fn json(value: Value) -> Self {
+        Self::new(StatusCode::OK, "application/json", value.to_string())
+    }
+
+    /// A token with a proof of work found within a few iterations.
+    pub fn requirements() -> Self {
+        Self::json(json!({
+            "token": "token",
+            "proofofwork": { "required": true, "seed": "0.42", "difficulty": "0fffff" },
+        }))
+    }
+
+    /// An event stream, to be filled with the builder methods.
+    pub fn stream() -> Self {
+        Self {
+            status: StatusCode::OK,
+            content_type: "text/event-stream",
+            chunks: vec![],
+        }
+    }
+
+    /// Stream the snapshots of the assistant message, then `[DONE]`.
+    pub fn answer(parts: &[&str]) -> Self {
+        parts
+            .iter()
+            .fold(Self::stream(), |res, part| res.text(part))
+            .done()
+    }
+
+    /// Send a snapshot of the assistant message.
+    pub fn text(self, text: &str) -> Self {
+        self.event(json!({
+            "message": {
+                "author": { "role": "assistant" },
+                "content": { "content_type": "text", "parts": [text] },
+                "status": "in_progress",
+            },
+        }))
+    }
+
+    pub fn event(self, data: Value) -> Self {
+        self.data(&data.to_string())
+    }
+
+    pub fn data(mut self, data: &str) -> Self {
+        self.chunks.push(Chunk::Data(format!("data: {data}\n\n")));
+        self
+    }
+
+    /// Send the bytes as they are, e.g. a frame that never ends.
+    pub fn raw(mut self, data: &str) -> Self {
+        self.chunks.push(Chunk::Data(data.to_string()));
+        self
+    }
+
+    pub fn done(self) -> Self {
+        self.data("[DONE]")
+    }
+
+    pub fn delay(mut self, millis: u64) -> Self {
+        self.chunks
+            .push(Chunk::Delay(Duration::from_millis(millis)));
+        self
+    }
+
+    /// Cut the connection instead of ending the stream.
+    pub fn cut(mut self) -> Self {
+        self.chunks.push(Chunk::Break);
+        self
+    }
+
+    fn into_response(self) -> Response<http_body_util::combinators::BoxBody<Bytes, io::Error>> {
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, io::Error>>(1);
+        tokio::spawn(async move {
+            for chunk in self.chunks {
+                match chunk {
+                    Chunk::Data(data) => {
+                        if tx.send(Ok(Frame::data(Bytes::from(data)))).await.is_err() {
+                            return;
+                        }
+                    }
+                    Chunk::Delay(delay) => tokio::time::sleep(delay).await,
+                    Chunk::Break => {
+                        let err = io::Error::new(io::ErrorKind::ConnectionReset, "cut");
+                        let _ = tx.send(Err(err)).await;
+                        return;
+                    }
+                }
+            }
+        });
+        Response::builder()
+            .status(self.status)
+            .header("Content-Type", self.content_type)
+            .body(BodyExt::boxed(StreamBody::new(ReceiverStream::new(rx))))
+            .unwrap()
+    }
+}
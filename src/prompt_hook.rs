@@ -0,0 +1,98 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::{process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Lets an external command rewrite the prompt before it is sent upstream. The command reads
+/// `{"system_prompt": ..., "prompt": ...}` on stdin and writes the same object on stdout.
+#[derive(Debug)]
+pub struct PromptHook {
+    command: String,
+    timeout: Duration,
+}
+
+impl PromptHook {
+    pub fn new(command: String, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    /// Run the hook, keeping the original prompt if it fails or takes too long.
+    pub async fn run(
+        &self,
+        req_id: &str,
+        system_prompt: Option<String>,
+        prompt: String,
+    ) -> (Option<String>, String) {
+        let input = json!({ "system_prompt": system_prompt, "prompt": prompt });
+        match tokio::time::timeout(self.timeout, self.execute(&input)).await {
+            Ok(Ok(output)) => {
+                let system_prompt = match output.get("system_prompt") {
+                    Some(Value::String(v)) => Some(v.clone()),
+                    Some(Value::Null) => None,
+                    _ => system_prompt,
+                };
+                let prompt = match output["prompt"].as_str() {
+                    Some(v) => v.to_string(),
+                    None => prompt,
+                };
+                (system_prompt, prompt)
+            }
+            Ok(Err(err)) => {
+                warn!("[{req_id}] Prompt hook failed, sending the original prompt, {err}");
+                (system_prompt, prompt)
+            }
+            Err(_) => {
+                warn!(
+                    "[{req_id}] Prompt hook timed out after {}s, sending the original prompt",
+                    self.timeout.as_secs()
+                );
+                (system_prompt, prompt)
+            }
+        }
+    }
+
+    async fn execute(&self, input: &Value) -> Result<Value> {
+        let mut child = shell_command(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("No stdin for the command"))?;
+        // Written while the output is read, a filter blocks on its full stdout pipe otherwise.
+        let input = input.to_string();
+        let write = async move {
+            let written = stdin.write_all(input.as_bytes()).await;
+            drop(stdin);
+            written
+        };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        // The exit status tells more than the broken pipe of a command ignoring its input.
+        match written {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => return Err(err.into()),
+            _ => {}
+        }
+        let output = output?;
+        if !output.status.success() {
+            bail!("The command exited with {}", output.status);
+        }
+        serde_json::from_slice(&output.stdout).map_err(|err| anyhow!("Invalid output, {err}"))
+    }
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
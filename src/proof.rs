@@ -0,0 +1,59 @@
+use ring::digest::{digest, SHA256, SHA512};
+use sha3::{Digest, Sha3_256, Sha3_512};
+use std::str::FromStr;
+
+/// How the proof of work is computed, so that a change of the sentinel can be followed by
+/// configuration instead of a rebuild.
+#[derive(Debug, Clone, Default)]
+pub struct ProofFormat {
+    pub algorithm: ProofAlgorithm,
+    /// Replaces the browser fingerprint, with `{screen}`, `{datetime}`, `{heap_size}`, `{nonce}`
+    /// and `{user_agent}` placeholders.
+    pub payload_template: Option<String>,
+}
+
+/// The hash of the seed and the encoded payload, whose prefix is compared with the difficulty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProofAlgorithm {
+    #[default]
+    Sha3_512,
+    Sha3_256,
+    Sha512,
+    Sha256,
+}
+
+impl ProofAlgorithm {
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha3_512 => Sha3_512::digest(data).to_vec(),
+            Self::Sha3_256 => Sha3_256::digest(data).to_vec(),
+            Self::Sha512 => digest(&SHA512, data).as_ref().to_vec(),
+            Self::Sha256 => digest(&SHA256, data).as_ref().to_vec(),
+        }
+    }
+}
+
+impl FromStr for ProofAlgorithm {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value.to_lowercase().replace('_', "-").as_str() {
+            "sha3-512" => Ok(Self::Sha3_512),
+            "sha3-256" => Ok(Self::Sha3_256),
+            "sha512" | "sha-512" => Ok(Self::Sha512),
+            "sha256" | "sha-256" => Ok(Self::Sha256),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for ProofAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sha3_512 => write!(f, "sha3-512"),
+            Self::Sha3_256 => write!(f, "sha3-256"),
+            Self::Sha512 => write!(f, "sha512"),
+            Self::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use serde_json::{Map, Value};
+
+const ROLES: [&str; 5] = ["system", "user", "assistant", "tool", "function"];
+const CONTENT_PART_TYPES: [&str; 2] = ["text", "image_url"];
+const RESPONSE_FORMAT_TYPES: [&str; 3] = ["text", "json_object", "json_schema"];
+const TOOL_CHOICES: [&str; 3] = ["none", "auto", "required"];
+const MESSAGE_FIELDS: [&str; 7] = [
+    "role",
+    "content",
+    "name",
+    "tool_calls",
+    "tool_call_id",
+    "function_call",
+    "refusal",
+];
+
+/// Validate a chat completion request against the OpenAI schema, returning a message per
+/// violation prefixed with the path of the offending field.
+pub fn validate_chat_request(body: &Value) -> Vec<String> {
+    let mut errors = vec![];
+    let Some(body) = body.as_object() else {
+        errors.push("the request body must be an object".into());
+        return errors;
+    };
+    for (name, value) in body {
+        let expected = match name.as_str() {
+            "messages" => {
+                validate_messages(value, &mut errors);
+                continue;
+            }
+            "model" | "user" => check(value.is_string(), "a string"),
+            "stream" | "logprobs" | "echo" | "store" | "parallel_tool_calls" => {
+                check(value.is_boolean(), "a boolean")
+            }
+            "temperature" => check(in_range(value, 0.0, 2.0), "a number between 0 and 2"),
+            "top_p" => check(in_range(value, 0.0, 1.0), "a number between 0 and 1"),
+            "presence_penalty" | "frequency_penalty" => {
+                check(in_range(value, -2.0, 2.0), "a number between -2 and 2")
+            }
+            "n" | "max_tokens" | "max_completion_tokens" => check(
+                value.as_u64().is_some_and(|v| v >= 1),
+                "an integer greater than 0",
+            ),
+            "top_logprobs" => check(
+                value.as_u64().is_some_and(|v| v <= 20),
+                "an integer between 0 and 20",
+            ),
+            "seed" => check(value.is_i64() || value.is_u64(), "an integer"),
+            "stop" => check(
+                value.is_string()
+                    || value
+                        .as_array()
+                        .is_some_and(|v| v.len() <= 4 && v.iter().all(|v| v.is_string())),
+                "a string or an array of at most 4 strings",
+            ),
+            "metadata" => check(
+                value
+                    .as_object()
+                    .is_some_and(|v| v.values().all(|v| v.is_string())),
+                "an object of strings",
+            ),
+            "logit_bias" => check(
+                value
+                    .as_object()
+                    .is_some_and(|v| v.values().all(|v| in_range(v, -100.0, 100.0))),
+                "an object of numbers between -100 and 100",
+            ),
+            "response_format" => {
+                validate_variant(value, "type", &RESPONSE_FORMAT_TYPES, name, &mut errors);
+                continue;
+            }
+            "stream_options" => check(value.is_object(), "an object"),
+            "tools" | "functions" => check(value.is_array(), "an array"),
+            "tool_choice" | "function_call" => {
+                if let Some(choice) = value.as_str() {
+                    if !TOOL_CHOICES.contains(&choice) {
+                        errors.push(unknown_variant(name, choice, &TOOL_CHOICES));
+                    }
+                    continue;
+                }
+                check(value.is_object(), "a string or an object")
+            }
+            _ => {
+                errors.push(unknown_field(name));
+                continue;
+            }
+        };
+        if let Some(expected) = expected {
+            errors.push(format!(
+                "{name}: expected {expected}, found {}",
+                kind(value)
+            ));
+        }
+    }
+    if !body.contains_key("messages") {
+        errors.push("messages: missing field".into());
+    }
+    errors
+}
+
+fn validate_messages(value: &Value, errors: &mut Vec<String>) {
+    let Some(messages) = value.as_array() else {
+        errors.push(format!(
+            "messages: expected an array, found {}",
+            kind(value)
+        ));
+        return;
+    };
+    if messages.is_empty() {
+        errors.push("messages: expected at least one message".into());
+    }
+    for (i, message) in messages.iter().enumerate() {
+        let path = format!("messages[{i}]");
+        let Some(message) = message.as_object() else {
+            errors.push(format!(
+                "{path}: expected an object, found {}",
+                kind(message)
+            ));
+            continue;
+        };
+        for name in message.keys() {
+            if !MESSAGE_FIELDS.contains(&name.as_str()) {
+                errors.push(unknown_field(&format!("{path}.{name}")));
+            }
+        }
+        let role = match &message.get("role") {
+            Some(Value::String(role)) if ROLES.contains(&role.as_str()) => role.as_str(),
+            Some(Value::String(role)) => {
+                errors.push(unknown_variant(&format!("{path}.role"), role, &ROLES));
+                continue;
+            }
+            Some(value) => {
+                errors.push(format!(
+                    "{path}.role: expected a string, found {}",
+                    kind(value)
+                ));
+                continue;
+            }
+            None => {
+                errors.push(format!("{path}.role: missing field"));
+                continue;
+            }
+        };
+        validate_content(message, role, &path, errors);
+        if role == "tool" && !message.get("tool_call_id").is_some_and(|v| v.is_string()) {
+            errors.push(format!("{path}.tool_call_id: missing field"));
+        }
+        if let Some(name) = message.get("name").filter(|v| !v.is_string()) {
+            errors.push(format!(
+                "{path}.name: expected a string, found {}",
+                kind(name)
+            ));
+        }
+    }
+}
+
+fn validate_content(
+    message: &Map<String, Value>,
+    role: &str,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    let calls_tools = message.contains_key("tool_calls") || message.contains_key("function_call");
+    match message.get("content") {
+        Some(Value::String(_)) => {}
+        None | Some(Value::Null) if role == "assistant" && calls_tools => {}
+        None => errors.push(format!("{path}.content: missing field")),
+        Some(Value::Array(parts)) => {
+            for (j, part) in parts.iter().enumerate() {
+                let part_path = format!("{path}.content[{j}]");
+                validate_variant(part, "type", &CONTENT_PART_TYPES, &part_path, errors);
+                if part["type"] == "text" && !part["text"].is_string() {
+                    errors.push(format!("{part_path}.text: expected a string"));
+                }
+            }
+        }
+        Some(value) => errors.push(format!(
+            "{path}.content: expected a string or an array of content parts, found {}",
+            kind(value)
+        )),
+    }
+}
+
+fn validate_variant(
+    value: &Value,
+    tag: &str,
+    variants: &[&str],
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    match value.get(tag) {
+        Some(Value::String(v)) if variants.contains(&v.as_str()) => {}
+        Some(Value::String(v)) => {
+            errors.push(unknown_variant(&format!("{path}.{tag}"), v, variants))
+        }
+        _ if !value.is_object() => {
+            errors.push(format!("{path}: expected an object, found {}", kind(value)))
+        }
+        _ => errors.push(format!("{path}.{tag}: missing field")),
+    }
+}
+
+fn check(valid: bool, expected: &'static str) -> Option<&'static str> {
+    (!valid).then_some(expected)
+}
+
+fn in_range(value: &Value, min: f64, max: f64) -> bool {
+    value.as_f64().is_some_and(|v| (min..=max).contains(&v))
+}
+
+fn unknown_variant(path: &str, value: &str, variants: &[&str]) -> String {
+    format!(
+        "{path}: unknown variant '{value}', expected one of {}",
+        variants.join(", ")
+    )
+}
+
+fn unknown_field(path: &str) -> String {
+    // Clients often send the camelCase spelling of a known field.
+    let name = path.rsplit('.').next().unwrap_or(path);
+    if name.chars().any(|c| c.is_ascii_uppercase()) {
+        let snake_case: String = name
+            .chars()
+            .flat_map(|c| {
+                let lower = c.to_ascii_lowercase();
+                let underscore = c.is_ascii_uppercase().then_some('_');
+                underscore.into_iter().chain(std::iter::once(lower))
+            })
+            .collect();
+        return format!("{path}: unknown field, did you mean '{snake_case}'?");
+    }
+    format!("{path}: unknown field")
+}
+
+/// Describe the value for an error message, spelling out numbers as they may just be out of range.
+fn kind(value: &Value) -> String {
+    match value {
+        Value::Null => "null".into(),
+        Value::Bool(_) => "a boolean".into(),
+        Value::Number(v) => v.to_string(),
+        Value::String(_) => "a string".into(),
+        Value::Array(_) => "an array".into(),
+        Value::Object(_) => "an object".into(),
+    }
+}
+
+/// Validate a value against a JSON Schema, supporting the keywords used by structured outputs:
+/// `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`, `items`, the
+/// length and range bounds, `anyOf`, `allOf` and local `$ref`s.
+pub fn validate_json(value: &Value, schema: &Value) -> Vec<String> {
+    let mut errors = vec![];
+    validate_json_at(value, schema, schema, "$", &mut errors);
+    errors
+}
+
+fn validate_json_at(
+    value: &Value,
+    schema: &Value,
+    root: &Value,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(reference) = schema["$ref"].as_str() {
+        match reference.strip_prefix('#').and_then(|v| root.pointer(v)) {
+            Some(schema) => validate_json_at(value, schema, root, path, errors),
+            None => errors.push(format!("{path}: unresolved $ref '{reference}'")),
+        }
+        return;
+    }
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(v) => vec![v.as_str()],
+            Value::Array(v) => v.iter().filter_map(|v| v.as_str()).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|v| is_type(value, v)) {
+            errors.push(format!(
+                "{path}: expected {}, found {}",
+                types.join(" or "),
+                kind(value)
+            ));
+            return;
+        }
+    }
+    if let Some(variants) = schema["enum"].as_array() {
+        if !variants.contains(value) {
+            errors.push(format!("{path}: {value} is not one of the allowed values"));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            errors.push(format!("{path}: expected {expected}"));
+        }
+    }
+    if let Some(schemas) = schema["allOf"].as_array() {
+        for schema in schemas {
+            validate_json_at(value, schema, root, path, errors);
+        }
+    }
+    if let Some(schemas) = schema["anyOf"].as_array() {
+        let matches = schemas
+            .iter()
+            .any(|schema| validate_json(value, &with_root(schema, root)).is_empty());
+        if !matches {
+            errors.push(format!("{path}: does not match any of the allowed schemas"));
+        }
+    }
+    match value {
+        Value::Object(object) => {
+            let properties = schema["properties"].as_object();
+            for name in schema["required"].as_array().into_iter().flatten() {
+                if let Some(name) = name.as_str().filter(|v| !object.contains_key(*v)) {
+                    errors.push(format!("{path}.{name}: missing field"));
+                }
+            }
+            for (name, field) in object {
+                let field_path = format!("{path}.{name}");
+                match properties.and_then(|v| v.get(name)) {
+                    Some(schema) => validate_json_at(field, schema, root, &field_path, errors),
+                    None => match &schema["additionalProperties"] {
+                        Value::Bool(false) => errors.push(format!("{field_path}: unknown field")),
+                        schema @ Value::Object(_) => {
+                            validate_json_at(field, schema, root, &field_path, errors)
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_bounds(
+                items.len(),
+                schema,
+                "minItems",
+                "maxItems",
+                "items",
+                path,
+                errors,
+            );
+            if schema["items"].is_object() {
+                for (i, item) in items.iter().enumerate() {
+                    let item_path = format!("{path}[{i}]");
+                    validate_json_at(item, &schema["items"], root, &item_path, errors);
+                }
+            }
+        }
+        Value::String(v) => {
+            let len = v.chars().count();
+            check_bounds(
+                len,
+                schema,
+                "minLength",
+                "maxLength",
+                "characters",
+                path,
+                errors,
+            );
+        }
+        Value::Number(v) => {
+            let v = v.as_f64().unwrap_or_default();
+            if schema["minimum"].as_f64().is_some_and(|min| v < min) {
+                errors.push(format!("{path}: must be at least {}", schema["minimum"]));
+            }
+            if schema["maximum"].as_f64().is_some_and(|max| v > max) {
+                errors.push(format!("{path}: must be at most {}", schema["maximum"]));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_bounds(
+    len: usize,
+    schema: &Value,
+    min: &str,
+    max: &str,
+    unit: &str,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(min) = schema[min].as_u64().filter(|v| (len as u64) < *v) {
+        errors.push(format!(
+            "{path}: expected at least {min} {unit}, found {len}"
+        ));
+    }
+    if let Some(max) = schema[max].as_u64().filter(|v| (len as u64) > *v) {
+        errors.push(format!(
+            "{path}: expected at most {max} {unit}, found {len}"
+        ));
+    }
+}
+
+/// Carry the definitions of the root over to a subschema validated on its own.
+fn with_root(schema: &Value, root: &Value) -> Value {
+    let mut schema = schema.clone();
+    for key in ["$defs", "definitions"] {
+        if let (Some(object), Some(defs)) = (schema.as_object_mut(), root.get(key)) {
+            object.entry(key).or_insert_with(|| defs.clone());
+        }
+    }
+    schema
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
@@ -0,0 +1,198 @@
+use crate::ResEvent;
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{
+    mpsc::{self, Receiver},
+    oneshot, Notify,
+};
+
+const CANCELLED: &str = "The identical requests sharing this completion were all cancelled";
+
+/// Share one upstream completion between identical concurrent requests, replaying its events
+/// to the requests that arrive while it is in flight.
+#[derive(Debug, Default)]
+pub struct SingleFlight {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+#[derive(Debug, Default)]
+struct Flight {
+    state: Mutex<FlightState>,
+    notify: Notify,
+    /// The requests still following the flight, the leading one included.
+    waiters: AtomicUsize,
+    /// Notified when the last waiter goes away.
+    abandoned: Notify,
+}
+
+#[derive(Debug, Default)]
+struct FlightState {
+    events: Vec<ResEvent>,
+    done: bool,
+}
+
+pub enum Join {
+    /// The first request, which must go upstream and `lead` the others.
+    Leader(FlightLeader),
+    /// An identical request is in flight, its events are replayed.
+    Follower(Receiver<ResEvent>),
+}
+
+impl SingleFlight {
+    pub fn join(&self, key: &str) -> Join {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(key) {
+            flight.waiters.fetch_add(1, Ordering::SeqCst);
+            return Join::Follower(follow(flight.clone()));
+        }
+        let flight = Arc::new(Flight::default());
+        flight.waiters.fetch_add(1, Ordering::SeqCst);
+        flights.insert(key.to_string(), flight.clone());
+        Join::Leader(FlightLeader {
+            flights: self.flights.clone(),
+            key: key.to_string(),
+            flight,
+            finished: false,
+        })
+    }
+}
+
+pub struct FlightLeader {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+    key: String,
+    flight: Arc<Flight>,
+    finished: bool,
+}
+
+impl FlightLeader {
+    /// Run the upstream completion in a task of its own and follow it like the other requests.
+    /// The task outlives the leading request, it is only dropped once no request follows it.
+    pub async fn lead<F>(mut self, completion: F) -> Result<Receiver<ResEvent>>
+    where
+        F: Future<Output = Result<Receiver<ResEvent>>> + Send + 'static,
+    {
+        let rx = follow(self.flight.clone());
+        let (started_tx, started_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let event = tokio::select! {
+                event = self.run(completion, started_tx) => event,
+                _ = self.abandoned() => Some(ResEvent::Error(CANCELLED.to_string())),
+            };
+            self.finish(event);
+        });
+        match started_rx.await {
+            Ok(Ok(())) => Ok(rx),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(anyhow!(CANCELLED)),
+        }
+    }
+
+    /// Record the events of the upstream completion for the followers, returning the error
+    /// ending them if it failed to start. The upstream is read to the end.
+    async fn run<F>(&self, completion: F, started: oneshot::Sender<Result<()>>) -> Option<ResEvent>
+    where
+        F: Future<Output = Result<Receiver<ResEvent>>>,
+    {
+        match completion.await {
+            Ok(mut rx) => {
+                let _ = started.send(Ok(()));
+                while let Some(event) = rx.recv().await {
+                    self.push(event);
+                }
+                None
+            }
+            Err(err) => {
+                let event = ResEvent::Error(err.to_string());
+                let _ = started.send(Err(err));
+                Some(event)
+            }
+        }
+    }
+
+    /// Wait until no request follows the flight anymore, after which none can join it.
+    async fn abandoned(&self) {
+        loop {
+            self.flight.abandoned.notified().await;
+            let mut flights = self.flights.lock().unwrap();
+            if self.flight.waiters.load(Ordering::SeqCst) == 0 {
+                self.leave(&mut flights);
+                return;
+            }
+        }
+    }
+
+    fn push(&self, event: ResEvent) {
+        self.flight.state.lock().unwrap().events.push(event);
+        self.flight.notify.notify_waiters();
+    }
+
+    /// Stop new requests from joining, unless an identical request already started a new flight.
+    fn leave(&self, flights: &mut HashMap<String, Arc<Flight>>) {
+        if flights
+            .get(&self.key)
+            .is_some_and(|v| Arc::ptr_eq(v, &self.flight))
+        {
+            flights.remove(&self.key);
+        }
+    }
+
+    fn finish(&mut self, event: Option<ResEvent>) {
+        self.finished = true;
+        self.leave(&mut self.flights.lock().unwrap());
+        let mut state = self.flight.state.lock().unwrap();
+        state.events.extend(event);
+        state.done = true;
+        drop(state);
+        self.flight.notify.notify_waiters();
+    }
+}
+
+impl Drop for FlightLeader {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish(Some(ResEvent::Error(CANCELLED.to_string())));
+        }
+    }
+}
+
+/// Replay the events of the flight to a request, which stops waiting once its receiver is gone.
+fn follow(flight: Arc<Flight>) -> Receiver<ResEvent> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut sent = 0;
+        loop {
+            let notified = flight.notify.notified();
+            let (events, done) = {
+                let state = flight.state.lock().unwrap();
+                (state.events[sent..].to_vec(), state.done)
+            };
+            sent += events.len();
+            let mut gone = false;
+            for event in events {
+                if tx.send(event).await.is_err() {
+                    gone = true;
+                    break;
+                }
+            }
+            if gone || done {
+                break;
+            }
+            tokio::select! {
+                _ = notified => {}
+                _ = tx.closed() => break,
+            }
+        }
+        if flight.waiters.fetch_sub(1, Ordering::SeqCst) == 1 {
+            flight.abandoned.notify_one();
+        }
+    });
+    rx
+}
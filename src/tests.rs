@@ -0,0 +1,3099 @@
+//! Tests of the server, end to end against the mock upstream, and of its helpers.
+
+use crate::config::Config;
+use crate::mock_upstream::{MockResponse, MockUpstream};
+use crate::proof::ProofFormat;
+use crate::*;
+
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use std::{
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc, Mutex, OnceLock},
+};
+use tokio::{net::TcpListener, sync::oneshot};
+
+struct TestServer {
+    addr: SocketAddr,
+    client: Client,
+    server: Arc<Server>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl TestServer {
+    /// Serve on a free port with the given settings, sending the upstream requests to `upstream`.
+    async fn start(upstream: &MockUpstream, vars: &[(&str, &str)]) -> Self {
+        let url = upstream.url();
+        let mut vars = vars.to_vec();
+        vars.push(("UPSTREAM_BASE_URL", &url));
+        Self::start_with(&vars).await
+    }
+
+    async fn start_with(vars: &[(&str, &str)]) -> Self {
+        let config = Config::from_vars(vars).unwrap();
+        let server = Arc::new(Server::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = server.clone().run(listener).await.unwrap();
+        let client = Client::builder().no_proxy().build().unwrap();
+        Self {
+            addr,
+            client,
+            server,
+            _stop: stop,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    fn post(&self, path: &str, body: &Value) -> RequestBuilder {
+        self.client
+            .post(self.url(path))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        self.client.get(self.url(path))
+    }
+
+    /// Complete without streaming, returning the response body.
+    async fn chat(&self, body: Value) -> Value {
+        let res = self
+            .post("/v1/chat/completions", &body)
+            .send()
+            .await
+            .unwrap();
+        res.json().await.unwrap()
+    }
+
+    /// Complete with streaming, returning the data of each event.
+    async fn stream(&self, mut body: Value) -> Vec<String> {
+        body["stream"] = true.into();
+        let res = self
+            .post("/v1/chat/completions", &body)
+            .send()
+            .await
+            .unwrap();
+        sse_data(&res.text().await.unwrap())
+    }
+}
+
+fn sse_data(text: &str) -> Vec<String> {
+    text.split("\n\n")
+        .filter_map(|v| v.strip_prefix("data: "))
+        .map(|v| v.to_string())
+        .collect()
+}
+
+/// The streamed chunks before `[DONE]`, parsed.
+fn chunks(data: &[String]) -> Vec<Value> {
+    data.iter()
+        .take_while(|v| *v != "[DONE]")
+        .map(|v| serde_json::from_str(v).unwrap())
+        .collect()
+}
+
+/// The content of the streamed chunks, joined.
+fn streamed_content(data: &[String]) -> String {
+    chunks(data)
+        .iter()
+        .filter_map(|v| {
+            v["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(|v| v.to_string())
+        })
+        .collect()
+}
+
+fn header<'a>(res: &'a reqwest::Response, name: &str) -> Option<&'a str> {
+    res.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn hello() -> Value {
+    json!({ "messages": [{ "role": "user", "content": "hi" }] })
+}
+
+fn content(body: &Value) -> &str {
+    body["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or_default()
+}
+
+fn finish_reason(body: &Value) -> &str {
+    body["choices"][0]["finish_reason"]
+        .as_str()
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn completes_from_the_upstream_answer() {
+    let upstream = MockUpstream::answer(&["Hello", "Hello, world!"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(content(&body), "Hello, world!");
+    assert_eq!(finish_reason(&body), "stop");
+    assert_eq!(body["object"], "chat.completion");
+    let conversation = &upstream.conversations()[0];
+    assert_eq!(
+        conversation.body["messages"][0]["content"]["parts"][0],
+        "hi"
+    );
+    assert!(conversation
+        .headers
+        .contains_key("openai-sentinel-proof-token"));
+}
+
+#[tokio::test]
+async fn streams_the_upstream_answer_as_deltas() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(20)
+            .text("Hello, world!")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let data = server.stream(hello()).await;
+    assert_eq!(data.last().unwrap(), "[DONE]");
+    let chunks = chunks(&data);
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert_eq!(streamed_content(&data), "Hello, world!");
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+}
+
+#[tokio::test]
+async fn reports_upstream_failures() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", "boom")
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    let message = body["error"]["message"].as_str().unwrap();
+    assert!(message.contains("500"), "{message}");
+}
+
+#[tokio::test]
+async fn reports_a_cut_upstream_connection() {
+    let upstream = MockUpstream::start(|_| MockResponse::stream().cut()).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    assert!(body["error"]["message"].is_string(), "{body}");
+}
+
+#[tokio::test]
+async fn sends_large_answers_in_chunks() {
+    let upstream =
+        MockUpstream::start(|_| MockResponse::answer(&[&"0123456789".repeat(5000)])).await;
+    let server = TestServer::start(&upstream, &[("CHUNKED_RESPONSE", "true")]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "transfer-encoding"), Some("chunked"));
+    assert_eq!(header(&res, "content-length"), None);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(content(&body), "0123456789".repeat(5000));
+}
+
+#[tokio::test]
+async fn reports_an_upstream_answer_without_content() {
+    let upstream = MockUpstream::start(|_| MockResponse::stream().done()).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"]["message"], "upstream produced no content");
+    assert_eq!(body["error"]["type"], "server_error");
+
+    let data = server.stream(hello()).await;
+    let error: Value = serde_json::from_str(&data[data.len() - 2]).unwrap();
+    assert_eq!(error["error"]["message"], "upstream produced no content");
+    assert_eq!(data.last().unwrap(), "[DONE]");
+}
+
+#[test]
+fn counts_the_proof_of_work_iterations() {
+    let format = ProofFormat::default();
+    let cancel = AtomicBool::new(false);
+    let (seed, diff) = (SELFTEST_SEED, SELFTEST_DIFFICULTY);
+    let (token, iterations) =
+        calculate_proof_token("test", &format, seed, diff, 1, &cancel).unwrap();
+    assert!(iterations > 0 && iterations < POW_MAX_ITERATIONS);
+    let base = token.strip_prefix(POW_TOKEN_PREFIX).unwrap();
+    assert!(meets_difficulty(&format, seed, base, diff));
+    let (token, iterations) =
+        calculate_proof_token("test", &format, seed, diff, 3, &cancel).unwrap();
+    assert!(iterations > 0 && iterations < POW_MAX_ITERATIONS);
+    let base = token.strip_prefix(POW_TOKEN_PREFIX).unwrap();
+    assert!(meets_difficulty(&format, seed, base, diff));
+}
+
+#[tokio::test]
+async fn sends_the_configured_history_flag() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    server.chat(hello()).await;
+    let server_off = TestServer::start(&upstream, &[("HISTORY_DISABLED", "false")]).await;
+    server_off.chat(hello()).await;
+    server_off
+        .post("/v1/chat/completions", &hello())
+        .header("X-History-Disabled", "true")
+        .send()
+        .await
+        .unwrap();
+    let flags: Vec<Value> = upstream
+        .conversations()
+        .iter()
+        .map(|v| v.body["history_and_training_disabled"].clone())
+        .collect();
+    assert_eq!(flags, [true, false, true]);
+}
+
+#[tokio::test]
+async fn streams_when_only_the_accept_header_asks() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .header("Accept", "text/event-stream")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "content-type"), Some("text/event-stream"));
+    let data = sse_data(&res.text().await.unwrap());
+    assert_eq!(streamed_content(&data), "Hi");
+
+    // The body field stays authoritative.
+    let mut body = hello();
+    body["stream"] = false.into();
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .header("Accept", "text/event-stream")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "content-type"), Some("application/json"));
+}
+
+#[tokio::test]
+async fn rejects_requests_with_too_many_messages() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("MAX_MESSAGES", "2")]).await;
+    let message = json!({ "role": "user", "content": "hi" });
+    let body = server
+        .chat(json!({ "messages": [message, message, message] }))
+        .await;
+    assert_eq!(
+        body["error"]["message"],
+        "Too many messages, the maximum allowed is 2"
+    );
+    let body = server.chat(json!({ "messages": [message, message] })).await;
+    assert_eq!(content(&body), "Hi");
+    assert_eq!(upstream.conversations().len(), 1);
+}
+
+#[tokio::test]
+async fn reports_the_configured_models_created() {
+    let server = TestServer::start_with(&[("MODELS_CREATED", "1700000000")]).await;
+    let res = server.get("/v1/models").send().await.unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["data"][0]["created"], 1700000000);
+    assert_eq!(body["data"][0]["id"], "gpt-3.5-turbo");
+}
+
+#[tokio::test]
+async fn shares_created_between_the_chunks() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("a")
+            .delay(1100)
+            .text("ab")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let chunks = chunks(&server.stream(hello()).await);
+    assert!(chunks.len() > 2);
+    assert!(chunks.iter().all(|v| v["created"] == chunks[0]["created"]));
+}
+
+#[tokio::test]
+async fn serves_the_playground_without_authorization() {
+    let server = TestServer::start_with(&[
+        ("ENABLE_PLAYGROUND", "true"),
+        ("AUTHORIZATION", "Bearer secret"),
+    ])
+    .await;
+    let res = server.get("/").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(header(&res, "content-type")
+        .unwrap()
+        .starts_with("text/html"));
+    let res = server.get("/v1/models").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn cancels_the_proof_of_work() {
+    let format = ProofFormat::default();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let canceller = cancel.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        canceller.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+    let start = std::time::Instant::now();
+    // An unsolvable difficulty, searched to the end it takes many seconds.
+    let err = calculate_proof_token("test", &format, "0.1", "00000000", 2, &cancel).unwrap_err();
+    assert!(err.to_string().contains("cancelled"), "{err}");
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn labels_the_flattened_messages() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let history = json!({ "messages": [
+        { "role": "user", "content": "hi" },
+        { "role": "assistant", "content": "hello" },
+        { "role": "user", "content": "how are you?" },
+    ] });
+    let server = TestServer::start(&upstream, &[("MESSAGE_TEMPLATE", "{role}: {content}")]).await;
+    server.chat(history.clone()).await;
+    let raw = TestServer::start(&upstream, &[]).await;
+    raw.chat(history).await;
+    let parts: Vec<Value> = upstream
+        .conversations()
+        .iter()
+        .map(|v| v.body["messages"][0]["content"]["parts"][0].clone())
+        .collect();
+    assert_eq!(parts[0], "User: hi\nAssistant: hello\nUser: how are you?");
+    assert_eq!(
+        parts[1],
+        "[INST]hi[/INST]\nhello\n[INST]how are you?[/INST]"
+    );
+}
+
+#[tokio::test]
+async fn describes_a_single_model() {
+    let server = TestServer::start_with(&[]).await;
+    let res = server.get("/v1/models/gpt-3.5-turbo").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let model: Value = res.json().await.unwrap();
+    let res = server.get("/v1/models").send().await.unwrap();
+    let models: Value = res.json().await.unwrap();
+    assert_eq!(model, models["data"][0]);
+
+    let res = server.get("/v1/models/gpt-5").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "model_not_found");
+}
+
+#[tokio::test]
+async fn includes_the_configured_system_fingerprint() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("SYSTEM_FINGERPRINT", "fp_test")]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(body["system_fingerprint"], "fp_test");
+    let chunks = chunks(&server.stream(hello()).await);
+    assert!(chunks.iter().all(|v| v["system_fingerprint"] == "fp_test"));
+
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    assert!(body.get("system_fingerprint").is_none(), "{body}");
+}
+
+#[tokio::test]
+async fn coerces_scalar_content_to_text() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    server
+        .chat(json!({ "messages": [{ "role": "user", "content": 42 }] }))
+        .await;
+    server
+        .chat(json!({ "messages": [{ "role": "user", "content": true }] }))
+        .await;
+    let parts: Vec<Value> = upstream
+        .conversations()
+        .iter()
+        .map(|v| v.body["messages"][0]["content"]["parts"][0].clone())
+        .collect();
+    assert_eq!(parts, ["42", "true"]);
+}
+
+#[tokio::test]
+async fn applies_the_upstream_connection_options() {
+    let config = Config::from_vars(&[
+        ("POOL_MAX_IDLE_PER_HOST", "4"),
+        ("POOL_IDLE_TIMEOUT_SECS", "30"),
+        ("UPSTREAM_HTTP2", "true"),
+    ])
+    .unwrap();
+    assert_eq!(config.pool_max_idle_per_host, Some(4));
+    assert_eq!(
+        config.pool_idle_timeout,
+        Some(std::time::Duration::from_secs(30))
+    );
+
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("UPSTREAM_HTTP2", "true")]).await;
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    let server = TestServer::start(&upstream, &[]).await;
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    let versions: Vec<http::Version> = upstream.conversations().iter().map(|v| v.version).collect();
+    assert_eq!(versions, [http::Version::HTTP_2, http::Version::HTTP_11]);
+}
+
+#[tokio::test]
+async fn skips_the_proof_of_work_until_forbidden() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("DISABLE_POW", "true")]).await;
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    let conversations = upstream.conversations();
+    assert_eq!(conversations.len(), 1);
+    assert!(!conversations[0]
+        .headers
+        .contains_key("openai-sentinel-proof-token"));
+
+    let upstream = MockUpstream::start(|req| {
+        if req.headers.contains_key("openai-sentinel-proof-token") {
+            MockResponse::answer(&["Hi"])
+        } else {
+            MockResponse::new(StatusCode::FORBIDDEN, "text/plain", "proof required")
+        }
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("DISABLE_POW", "true")]).await;
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    let proofs: Vec<bool> = upstream
+        .conversations()
+        .iter()
+        .map(|v| v.headers.contains_key("openai-sentinel-proof-token"))
+        .collect();
+    assert_eq!(proofs, [false, true]);
+}
+
+#[tokio::test]
+async fn times_out_by_the_request_header() {
+    let upstream =
+        MockUpstream::start(|_| MockResponse::stream().delay(5000).text("Hi").done()).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let start = std::time::Instant::now();
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .header("X-Upstream-Timeout-Ms", "200")
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+    let message = body["error"]["message"].as_str().unwrap();
+    assert!(message.contains("timed out after 200ms"), "{message}");
+}
+
+#[test]
+fn reads_parameters_in_both_naming_styles() {
+    let body = json!({
+        "max_tokens": 10,
+        "topP": 0.5,
+        "responseFormat": { "type": "json_object" },
+        "stream": "true",
+    });
+    assert_eq!(get_param(&body, "max_tokens"), 10);
+    assert_eq!(get_param(&body, "top_p"), 0.5);
+    assert_eq!(get_param(&body, "response_format")["type"], "json_object");
+    assert!(get_param(&body, "temperature").is_null());
+    assert_eq!(get_bool_param(&body, "stream"), Some(true));
+    let body = json!({ "max_tokens": 10, "maxTokens": 20 });
+    assert_eq!(get_param(&body, "max_tokens"), 10);
+}
+
+#[test]
+fn lists_every_configuration_problem() {
+    let err = Config::from_vars(&[
+        ("PORT", "http"),
+        ("ALL_PROXY", "ftp://localhost:21"),
+        ("BASE_PATH", "api"),
+        ("MAX_MESSAGES", "0"),
+    ])
+    .unwrap_err()
+    .to_string();
+    assert_eq!(
+        err,
+        "Invalid configuration:
+  - $PORT: invalid value 'http'
+  - $ALL_PROXY: unsupported proxy 'ftp://localhost:21', expected one of http://, https://, socks5://, socks5h://
+  - $BASE_PATH: must start with '/', e.g. '/api'
+  - $MAX_MESSAGES: must be greater than 0"
+    );
+    let err = Config::from_vars(&[("FALLBACK_ENABLED", "true")])
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains(
+            "$FALLBACK_ENABLED: must not be true unless $CIRCUIT_BREAKER_THRESHOLD is set"
+        ),
+        "{err}"
+    );
+    assert!(Config::from_vars(&[]).is_ok());
+}
+
+#[tokio::test]
+async fn truncates_completions_at_the_maximum_duration() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(3000)
+            .text("Hello, world!")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("MAX_COMPLETION_SECS", "1")]).await;
+    let start = std::time::Instant::now();
+    let body = server.chat(hello()).await;
+    assert!(start.elapsed() < std::time::Duration::from_millis(2500));
+    assert_eq!(content(&body), "Hello");
+    assert_eq!(finish_reason(&body), "length");
+
+    let chunks = chunks(&server.stream(hello()).await);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "length"
+    );
+}
+
+#[tokio::test]
+async fn returns_the_partial_content_of_an_interrupted_stream() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .text("Hello, wor")
+            .delay(20)
+            .cut()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(content(&body), "Hello, wor");
+    assert_eq!(finish_reason(&body), "length");
+
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hello, wor");
+    let chunks = chunks(&data);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "length"
+    );
+    assert_eq!(data.last().unwrap(), "[DONE]");
+}
+
+#[tokio::test]
+async fn coalesces_the_streamed_deltas() {
+    const TEXT: &str = "The quick brown fox jumps over the lazy dog";
+    let upstream = MockUpstream::start(|_| {
+        (1..=TEXT.len())
+            .fold(MockResponse::stream(), |res, i| res.text(&TEXT[..i]))
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let plain = server.stream(hello()).await;
+    let server = TestServer::start(&upstream, &[("COALESCE_CHARS", "10")]).await;
+    let coalesced = server.stream(hello()).await;
+    assert_eq!(streamed_content(&plain), TEXT);
+    assert_eq!(streamed_content(&coalesced), TEXT);
+    // The role delta, the text, the finish and `[DONE]`.
+    assert_eq!(plain.len(), TEXT.len() + 3);
+    assert!(coalesced.len() <= TEXT.len() / 10 + 4, "{coalesced:?}");
+}
+
+#[tokio::test]
+async fn reports_the_api_version() {
+    let server = TestServer::start_with(&[]).await;
+    let res = server.get("/v1/models").send().await.unwrap();
+    assert_eq!(
+        header(&res, "x-api-version"),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+    let res = server.get("/missing").send().await.unwrap();
+    assert!(!header(&res, "x-api-version").unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn refuses_prompts_with_blocked_words() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("BLOCKED_WORDS", "secret, Forbidden")]).await;
+    let body = server
+        .chat(json!({ "messages": [{ "role": "user", "content": "Tell me the FORBIDDEN thing" }] }))
+        .await;
+    assert_eq!(finish_reason(&body), "content_filter");
+    assert_eq!(upstream.conversations().len(), 0);
+
+    // Only whole words are blocked.
+    let body = server
+        .chat(json!({ "messages": [{ "role": "user", "content": "Tell me about secretaries" }] }))
+        .await;
+    assert_eq!(content(&body), "Hi");
+    assert_eq!(finish_reason(&body), "stop");
+    assert_eq!(upstream.conversations().len(), 1);
+}
+
+#[tokio::test]
+async fn strips_the_markdown_of_the_answer() {
+    const MARKDOWN: &str = "# Title\nSome **bold** and *emphasis* with a [link](http://x).\n```rust\nlet a = 1;\n```\n* item\n";
+    const TEXT: &str = "Title\nSome bold and emphasis with a link.\nlet a = 1;\n- item\n";
+    // Snapshots that split the markup across the deltas.
+    let upstream = MockUpstream::start(|_| {
+        (1..=MARKDOWN.len() / 5)
+            .map(|i| &MARKDOWN[..i * 5])
+            .chain([MARKDOWN])
+            .fold(MockResponse::stream(), |res, text| res.text(text))
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("STRIP_MARKDOWN", "true")]).await;
+    assert_eq!(content(&server.chat(hello()).await), TEXT);
+    assert_eq!(streamed_content(&server.stream(hello()).await), TEXT);
+
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .header("X-Strip-Markdown", "true")
+        .send()
+        .await
+        .unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(content(&body), TEXT);
+    assert_eq!(content(&server.chat(hello()).await), MARKDOWN);
+}
+
+#[tokio::test]
+async fn accepts_ipv6_and_ipv4_when_dual_stack() {
+    let Ok(listener) = bind_listener("[::]:0".parse().unwrap()) else {
+        eprintln!("IPv6 is not supported here, skipping");
+        return;
+    };
+    let port = listener.local_addr().unwrap().port();
+    let server = Arc::new(Server::new(Config::from_vars(&[]).unwrap()).unwrap());
+    let _stop = server.run(listener).await.unwrap();
+    let client = Client::builder().no_proxy().build().unwrap();
+    for host in ["[::1]", "127.0.0.1"] {
+        let res = client
+            .get(format!("http://{host}:{port}/v1/models"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{host}");
+    }
+}
+
+/// Keep the log lines of every test, they are told apart by their request ids.
+fn captured_logs() -> &'static Mutex<Vec<String>> {
+    static LOGS: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+    struct Capture;
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            if let Some(logs) = LOGS.get() {
+                logs.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+    LOGS.get_or_init(|| {
+        log::set_boxed_logger(Box::new(Capture)).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+        Default::default()
+    })
+}
+
+#[tokio::test]
+async fn tags_the_logs_of_concurrent_requests() {
+    let logs = captured_logs();
+    let upstream =
+        MockUpstream::start(|_| MockResponse::stream().delay(100).text("Hi").done()).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let request = |content: &'static str| {
+        server
+            .post(
+                "/v1/chat/completions",
+                &json!({ "messages": [{ "role": "user", "content": content }] }),
+            )
+            .send()
+    };
+    let (alpha, beta) = tokio::join!(request("alpha"), request("beta"));
+    let ids = [alpha.unwrap(), beta.unwrap()]
+        .map(|res| header(&res, "x-request-id").unwrap().to_string());
+    assert_ne!(ids[0], ids[1]);
+    let logs = logs.lock().unwrap();
+    for (id, content, other) in [(&ids[0], "alpha", "beta"), (&ids[1], "beta", "alpha")] {
+        let lines: Vec<&String> = logs
+            .iter()
+            .filter(|v| v.starts_with(&format!("[{id}]")))
+            .collect();
+        assert!(lines
+            .iter()
+            .any(|v| v.contains("req body") && v.contains(content)));
+        assert!(!lines.iter().any(|v| v.contains(other)));
+        assert!(lines
+            .iter()
+            .any(|v| v.contains("POST /v1/chat/completions 200")));
+    }
+}
+
+#[tokio::test]
+async fn wraps_the_answer_with_the_prefix_and_suffix() {
+    let upstream = MockUpstream::answer(&["Hello", "Hello, world!"]).await;
+    let server = TestServer::start(
+        &upstream,
+        &[
+            ("RESPONSE_PREFIX", "Bot: "),
+            ("RESPONSE_SUFFIX", "\\n--\\nDisclaimer"),
+        ],
+    )
+    .await;
+    let expected = "Bot: Hello, world!\n--\nDisclaimer";
+    assert_eq!(content(&server.chat(hello()).await), expected);
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), expected);
+    let chunks = chunks(&data);
+    assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "Bot: ");
+}
+
+#[tokio::test]
+async fn answers_head_requests_without_a_body() {
+    let server = TestServer::start_with(&[]).await;
+    for path in ["/v1/models", "/ready"] {
+        let get = server.get(path).send().await.unwrap();
+        let res = server.client.head(server.url(path)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{path}");
+        assert_eq!(header(&res, "content-type"), header(&get, "content-type"));
+        assert!(res.bytes().await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn sends_the_configured_sentinel_headers() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("UPSTREAM_PRIORITY", "u=0")]).await;
+    server.chat(hello()).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    server.chat(hello()).await;
+    let priorities: Vec<String> = upstream
+        .requests()
+        .iter()
+        .map(|v| v.headers["priority"].to_str().unwrap().to_string())
+        .collect();
+    assert_eq!(priorities, ["u=0", "u=0", "u=1, i", "u=1, i"]);
+
+    let err = Config::from_vars(&[("UPSTREAM_PRIORITY", "u=1\r\nx: y")])
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("$UPSTREAM_PRIORITY"), "{err}");
+}
+
+#[tokio::test]
+async fn downgrades_streams_the_accept_header_rejects() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    for (strict, content_type) in [("false", "text/event-stream"), ("true", "application/json")] {
+        let server = TestServer::start(&upstream, &[("STRICT_ACCEPT", strict)]).await;
+        let res = server
+            .post("/v1/chat/completions", &body)
+            .header("Accept", "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(header(&res, "content-type"), Some(content_type), "{strict}");
+        let res = server
+            .post("/v1/chat/completions", &body)
+            .header("Accept", "text/event-stream, application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(header(&res, "content-type"), Some("text/event-stream"));
+    }
+}
+
+#[tokio::test]
+async fn drops_connections_with_slow_headers() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = TestServer::start_with(&[("HEADER_READ_TIMEOUT_SECS", "1")]).await;
+    let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    stream
+        .write_all(b"GET /v1/models HTTP/1.1\r\nHost: localhost\r\n")
+        .await
+        .unwrap();
+    let start = std::time::Instant::now();
+    let mut buf = vec![];
+    let read = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        stream.read_to_end(&mut buf),
+    )
+    .await;
+    assert!(read.is_ok(), "the connection was kept open");
+    assert!(start.elapsed() >= std::time::Duration::from_millis(900));
+    assert!(!String::from_utf8_lossy(&buf).contains("200 OK"));
+}
+
+#[tokio::test]
+async fn describes_the_model_capabilities() {
+    let server = TestServer::start_with(&[("MODEL_CONTEXT_WINDOW", "16384")]).await;
+    let res = server.get("/v1/models").send().await.unwrap();
+    let body: Value = res.json().await.unwrap();
+    let model = &body["data"][0];
+    assert_eq!(model["object"], "model");
+    assert_eq!(model["owned_by"], "openai");
+    assert_eq!(model["context_window"], 16384);
+    assert_eq!(model["max_output_tokens"], config::MODEL_MAX_OUTPUT_TOKENS);
+    assert_eq!(model["capabilities"]["streaming"], true);
+}
+
+#[tokio::test]
+async fn resumes_a_cut_upstream_stream() {
+    let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let upstream = MockUpstream::start(move |_| {
+        if counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed) == 0 {
+            MockResponse::stream().text("Hello").delay(20).cut()
+        } else {
+            MockResponse::answer(&["Hello", "Hello, world!"])
+        }
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("AUTO_RESUME", "true")]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(content(&body), "Hello, world!");
+    assert_eq!(finish_reason(&body), "stop");
+    assert_eq!(upstream.conversations().len(), 2);
+    // The requirements are reused for the resumed conversation.
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn cancels_a_streaming_completion() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(5000)
+            .text("Hello, world!")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let mut res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    let mut text = String::new();
+    while !text.contains("Hello") {
+        let chunk = res.chunk().await.unwrap().unwrap();
+        text.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let id = chunks(&sse_data(&text))[0]["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let start = std::time::Instant::now();
+    let path = format!("/v1/chat/completions/{id}");
+    let cancel = server
+        .client
+        .delete(server.url(&path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cancel.status(), StatusCode::OK);
+    while let Some(chunk) = res.chunk().await.unwrap() {
+        text.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    let data = sse_data(&text);
+    assert_eq!(streamed_content(&data), "Hello");
+    assert_eq!(data.last().unwrap(), "[DONE]");
+
+    let cancel = server
+        .client
+        .delete(server.url(&path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cancel.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn writes_and_rotates_the_log_file() {
+    let dir = std::env::temp_dir().join(format!("chatgpt-free-api-logs-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.log");
+    let file = RotatingFile::open(&path, Some(100), 2).unwrap();
+    let logger = env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Info)
+        .target(env_logger::Target::Pipe(Box::new(LogWriter::new(
+            file, false,
+        ))))
+        .build();
+    for i in 0..10 {
+        log::Log::log(
+            &logger,
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .args(format_args!("[req-{i}] 127.0.0.1 GET /v1/models 200"))
+                .build(),
+        );
+    }
+    log::Log::flush(&logger);
+    let current = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        current.contains("[req-9] 127.0.0.1 GET /v1/models 200"),
+        "{current}"
+    );
+    assert!(dir.join("server.log.1").exists());
+    assert!(dir.join("server.log.2").exists());
+    assert!(!dir.join("server.log.3").exists());
+    for name in ["server.log", "server.log.1", "server.log.2"] {
+        let size = std::fs::metadata(dir.join(name)).unwrap().len();
+        assert!(size <= 100, "{name} is {size} bytes");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn paces_the_streamed_frames() {
+    const TEXT: &str = "abcdefghijklmnopqrst";
+    let upstream = MockUpstream::start(|_| {
+        (1..=TEXT.len())
+            .fold(MockResponse::stream(), |res, i| {
+                res.text(&TEXT[..i]).delay(15)
+            })
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("MIN_FRAME_INTERVAL_MS", "100")]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let mut res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    let (mut text, mut arrivals) = (String::new(), vec![]);
+    while let Some(chunk) = res.chunk().await.unwrap() {
+        let chunk = String::from_utf8_lossy(&chunk).to_string();
+        let contents = streamed_content(&sse_data(&chunk));
+        if !contents.is_empty() {
+            arrivals.push(std::time::Instant::now());
+        }
+        text.push_str(&chunk);
+    }
+    let last = *arrivals.last().unwrap();
+    assert_eq!(streamed_content(&sse_data(&text)), TEXT);
+    // 20 deltas over about 300ms, paced into a few frames.
+    assert!(arrivals.len() < TEXT.len() / 2, "{}", arrivals.len());
+    // The last frame is the buffer drained at the end, the others keep the interval.
+    for pair in arrivals[..arrivals.len() - 1].windows(2) {
+        assert!(pair[1] - pair[0] >= std::time::Duration::from_millis(90));
+    }
+    // The end is not held back by the pacing.
+    assert!(last.elapsed() < std::time::Duration::from_millis(90));
+}
+
+#[tokio::test]
+async fn requires_a_json_content_type() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let url = server.url("/v1/chat/completions");
+    let cases = [
+        (
+            None,
+            Some("Missing Content-Type header, expected 'application/json'"),
+        ),
+        (
+            Some("text/plain"),
+            Some("Unsupported Content-Type 'text/plain', expected 'application/json'"),
+        ),
+        (Some("application/json; charset=utf-8"), None),
+        (Some("application/merge-patch+json"), None),
+    ];
+    for (content_type, error) in cases {
+        let mut req = server.client.post(&url).body(hello().to_string());
+        if let Some(content_type) = content_type {
+            req = req.header("Content-Type", content_type);
+        }
+        let res = req.send().await.unwrap();
+        let status = res.status();
+        let body: Value = res.json().await.unwrap();
+        match error {
+            Some(error) => {
+                assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                assert_eq!(body["error"]["message"], error);
+            }
+            None => assert_eq!(content(&body), "Hi", "{content_type:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn requires_a_non_empty_messages_array() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    for body in [
+        json!({}),
+        json!({ "messages": "hi" }),
+        json!({ "messages": [] }),
+    ] {
+        let res = server.chat(body).await;
+        assert_eq!(
+            res["error"]["message"],
+            "'messages' is required and must be a non-empty array"
+        );
+        assert_eq!(res["error"]["type"], "invalid_request_error");
+    }
+    assert!(upstream.requests().is_empty());
+}
+
+#[test]
+fn builds_the_proof_payload_from_the_template() {
+    let datetime = "Mon Jan 01 2024 00:00:00 GMT+0000 (Coordinated Universal Time)";
+    let payload: Value =
+        serde_json::from_str(&proof_payload(&ProofFormat::default(), datetime, 7)).unwrap();
+    assert_eq!(
+        payload,
+        json!([*PROOF_V1, datetime, POW_HEAP_SIZE_LIMIT, 7, USER_AGENT])
+    );
+
+    // The documented template reproduces the built-in payload.
+    let format = ProofFormat {
+        payload_template: Some(
+            r#"[{screen},"{datetime}",{heap_size},{nonce},"{user_agent}"]"#.into(),
+        ),
+        ..Default::default()
+    };
+    assert_eq!(
+        proof_payload(&format, datetime, 7),
+        proof_payload(&ProofFormat::default(), datetime, 7)
+    );
+    let format = ProofFormat {
+        payload_template: Some(r#"{"n":{nonce},"h":{heap_size}}"#.into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        proof_payload(&format, datetime, 7),
+        r#"{"n":7,"h":4294705152}"#
+    );
+}
+
+/// A bare WebSocket client: the upgrade, masked text frames out, unmasked frames in.
+struct WsClient {
+    stream: tokio::net::TcpStream,
+}
+
+impl WsClient {
+    /// Upgrade, returning the status line and headers when the server refuses.
+    async fn connect(addr: SocketAddr, headers: &str) -> Result<Self, String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let request = format!(
+            "GET /v1/chat/completions/ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n{headers}\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        if !head.starts_with("HTTP/1.1 101") {
+            return Err(head);
+        }
+        assert!(head.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{head}");
+        Ok(Self { stream })
+    }
+
+    async fn send(&mut self, text: &str) {
+        use tokio::io::AsyncWriteExt;
+
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![0x81, 0x80 | 126];
+        frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&mask);
+        frame.extend(text.bytes().enumerate().map(|(i, v)| v ^ mask[i % 4]));
+        self.stream.write_all(&frame).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut head = [0u8; 2];
+        self.stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0x81);
+        let len = match head[1] {
+            126 => self.stream.read_u16().await.unwrap() as usize,
+            127 => self.stream.read_u64().await.unwrap() as usize,
+            v => v as usize,
+        };
+        let mut payload = vec![0; len];
+        self.stream.read_exact(&mut payload).await.unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    /// The messages of one completion, up to `[DONE]`.
+    async fn completion(&mut self, body: &Value) -> Vec<String> {
+        self.send(&body.to_string()).await;
+        let mut data = vec![];
+        while data.last().map(|v: &String| v.as_str()) != Some("[DONE]") {
+            data.push(self.recv().await);
+        }
+        data
+    }
+}
+
+#[tokio::test]
+async fn completes_over_a_websocket() {
+    let upstream = MockUpstream::answer(&["Hello", "Hello, world!"]).await;
+    let server = TestServer::start(&upstream, &[("OAI_DEVICE_IDS", "device-a,device-b")]).await;
+    let mut ws = WsClient::connect(server.addr, "").await.unwrap();
+    for _ in 0..2 {
+        let data = ws.completion(&hello()).await;
+        assert_eq!(streamed_content(&data), "Hello, world!");
+        assert_eq!(
+            chunks(&data).last().unwrap()["choices"][0]["finish_reason"],
+            "stop"
+        );
+    }
+    ws.send(&json!({ "messages": [] }).to_string()).await;
+    let error: Value = serde_json::from_str(&ws.recv().await).unwrap();
+    assert!(error["error"]["message"].is_string(), "{error}");
+
+    // Each message is a request of its own, with the next device id.
+    let device_ids: Vec<String> = upstream
+        .conversations()
+        .iter()
+        .map(|v| v.headers["oai-device-id"].to_str().unwrap().to_string())
+        .collect();
+    assert_eq!(device_ids.len(), 2);
+    assert_ne!(device_ids[0], device_ids[1]);
+
+    let err = WsClient::connect(server.addr, "X-Upstream-Timeout-Ms: soon\r\n")
+        .await
+        .err()
+        .unwrap();
+    assert!(err.starts_with("HTTP/1.1 400"), "{err}");
+}
+
+#[tokio::test]
+async fn reports_cloudflare_challenges_concisely() {
+    let page = r#"<!DOCTYPE html><html lang="en-US"><head><title>Just a moment...</title></head><body><div class="main-wrapper" role="main"><noscript>Enable JavaScript and cookies to continue</noscript></div><script>(function(){window._cf_chl_opt={cvId: '3',cZone: "chat.openai.com",cType: 'managed'};var a = document.createElement('script');a.src = '/cdn-cgi/challenge-platform/h/b/orchestrate/chl_page/v1';})();</script></body></html>"#;
+    let upstream = MockUpstream::start_with(move |_| {
+        MockResponse::new(StatusCode::FORBIDDEN, "text/html; charset=UTF-8", page)
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "upstream blocked by Cloudflare challenge; try a different proxy"
+    );
+}
+
+/// Run with `cargo test --release -- --ignored benchmarks_the_proof_of_work_threads --nocapture`.
+#[test]
+#[ignore]
+fn benchmarks_the_proof_of_work_threads() {
+    let format = ProofFormat::default();
+    let cancel = AtomicBool::new(false);
+    let threads = std::thread::available_parallelism().map_or(1, |v| v.get());
+    let time = |threads| {
+        let start = std::time::Instant::now();
+        for i in 0..20 {
+            let seed = format!("0.{i}");
+            calculate_proof_token("bench", &format, &seed, "000f", threads, &cancel).unwrap();
+        }
+        start.elapsed()
+    };
+    let single = time(1);
+    let parallel = time(threads);
+    println!("time to 20 proofs: 1 thread {single:?}, {threads} threads {parallel:?}");
+    if threads > 1 {
+        assert!(parallel < single);
+    }
+}
+
+#[tokio::test]
+async fn includes_null_logprobs_when_requested() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let has_logprobs = |v: &Value| {
+        v["choices"][0]
+            .as_object()
+            .unwrap()
+            .contains_key("logprobs")
+    };
+    let mut body = hello();
+    body["logprobs"] = true.into();
+    let res = server.chat(body.clone()).await;
+    assert!(has_logprobs(&res));
+    assert!(res["choices"][0]["logprobs"].is_null());
+    let streamed = chunks(&server.stream(body).await);
+    assert!(streamed.iter().all(has_logprobs));
+
+    let res = server.chat(hello()).await;
+    assert!(!has_logprobs(&res));
+    let streamed = chunks(&server.stream(hello()).await);
+    assert!(!streamed.iter().any(has_logprobs));
+}
+
+#[tokio::test]
+async fn streams_by_default_when_configured() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("DEFAULT_STREAM", "true")]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "content-type"), Some("text/event-stream"));
+    assert_eq!(
+        streamed_content(&sse_data(&res.text().await.unwrap())),
+        "Hi"
+    );
+
+    let mut body = hello();
+    body["stream"] = false.into();
+    assert_eq!(content(&server.chat(body).await), "Hi");
+}
+
+#[test]
+fn truncates_between_graphemes() {
+    use transform::truncate_chars;
+
+    assert_eq!(truncate_chars("hello", 10), "hello");
+    assert_eq!(truncate_chars("hello", 3), "hel");
+    // "é" as "e" and a combining acute accent.
+    assert_eq!(truncate_chars("cafe\u{301}s", 4), "caf");
+    assert_eq!(truncate_chars("cafe\u{301}s", 5), "cafe\u{301}");
+    // A thumbs up with a skin tone modifier.
+    assert_eq!(truncate_chars("ok \u{1F44D}\u{1F3FD}!", 4), "ok ");
+    // A family joined by zero-width joiners, and a flag of two regional indicators.
+    let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+    assert_eq!(truncate_chars(&format!("a{family}b"), 5), "a");
+    assert_eq!(
+        truncate_chars(&format!("a{family}b"), 6),
+        format!("a{family}")
+    );
+    assert_eq!(
+        truncate_chars("\u{1F1EB}\u{1F1F7}\u{1F1E9}\u{1F1EA}", 3),
+        "\u{1F1EB}\u{1F1F7}"
+    );
+    // Hangul syllables spelled with conjoining jamo.
+    assert_eq!(truncate_chars("\u{1100}\u{1161}\u{11A8}x", 2), "");
+}
+
+#[tokio::test]
+async fn caps_the_response_length() {
+    let upstream = MockUpstream::answer(&["Hello", "Hello, wo", "Hello, world!"]).await;
+    let server = TestServer::start(&upstream, &[("MAX_RESPONSE_CHARS", "8")]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(content(&body), "Hello, w");
+    assert_eq!(finish_reason(&body), "length");
+
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hello, w");
+    assert_eq!(
+        chunks(&data).last().unwrap()["choices"][0]["finish_reason"],
+        "length"
+    );
+
+    let server = TestServer::start(&upstream, &[("MAX_RESPONSE_CHARS", "13")]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(content(&body), "Hello, world!");
+    assert_eq!(finish_reason(&body), "stop");
+}
+
+#[tokio::test]
+async fn tells_a_missing_key_from_a_wrong_one() {
+    let server = TestServer::start_with(&[("AUTHORIZATION", "Bearer secret")]).await;
+    let cases = [
+        (None, "Missing Authorization header"),
+        (Some("Bearer wrong"), "Invalid Authorization header value"),
+        (Some("secret"), "Invalid Authorization header value"),
+    ];
+    for (authorization, error) in cases {
+        let mut req = server.get("/v1/models");
+        if let Some(authorization) = authorization {
+            req = req.header("Authorization", authorization);
+        }
+        let res = req.send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["error"]["type"], "authentication_error");
+        assert_eq!(
+            body["error"]["message"],
+            format!(
+                "{error}, expected the configured key in the format 'Authorization: Bearer <key>'."
+            )
+        );
+    }
+    let res = server
+        .get("/v1/models")
+        .header("Authorization", "Bearer secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[test]
+fn matches_only_the_exact_key() {
+    assert!(key_matches(b"Bearer secret", b"Bearer secret"));
+    assert!(!key_matches(b"Bearer secre", b"Bearer secret"));
+    assert!(!key_matches(b"Bearer secrets", b"Bearer secret"));
+    assert!(!key_matches(b"Bearer Secret", b"Bearer secret"));
+    assert!(!key_matches(b"", b"Bearer secret"));
+}
+
+#[tokio::test]
+async fn forwards_and_echoes_the_seed() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["seed"] = 42.into();
+    let res = server.chat(body.clone()).await;
+    assert_eq!(res["seed"], 42);
+    let streamed = chunks(&server.stream(body).await);
+    assert!(streamed.iter().all(|v| v["seed"] == 42));
+    server.chat(hello()).await;
+    let seeds: Vec<Value> = upstream
+        .conversations()
+        .iter()
+        .map(|v| v.body["seed"].clone())
+        .collect();
+    assert_eq!(seeds, [json!(42), json!(42), Value::Null]);
+}
+
+#[tokio::test]
+async fn opens_and_closes_the_circuit() {
+    let failing = Arc::new(AtomicBool::new(true));
+    let upstream_failing = failing.clone();
+    let upstream = MockUpstream::start(move |_| {
+        if upstream_failing.load(std::sync::atomic::Ordering::Relaxed) {
+            MockResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", "down")
+        } else {
+            MockResponse::answer(&["Hi"])
+        }
+    })
+    .await;
+    let server = TestServer::start(
+        &upstream,
+        &[
+            ("CIRCUIT_BREAKER_THRESHOLD", "2"),
+            ("CIRCUIT_BREAKER_COOLDOWN_SECS", "1"),
+        ],
+    )
+    .await;
+    let circuit = || async {
+        let res = server.get("/ready").send().await.unwrap();
+        res.json::<Value>().await.unwrap()["circuit"].clone()
+    };
+    assert_eq!(
+        circuit().await,
+        json!({ "state": "closed", "retry_after": 0 })
+    );
+    server.chat(hello()).await;
+    server.chat(hello()).await;
+    assert_eq!(
+        circuit().await,
+        json!({ "state": "open", "retry_after": 1 })
+    );
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(upstream.conversations().len(), 2);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(circuit().await["state"], "half-open");
+    failing.store(false, std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    assert_eq!(
+        circuit().await,
+        json!({ "state": "closed", "retry_after": 0 })
+    );
+}
+
+#[tokio::test]
+async fn finishes_refusals_with_content_filter() {
+    const REFUSAL: &str = "I can't help with that.";
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .event(json!({
+                "message": {
+                    "author": { "role": "assistant" },
+                    "content": { "content_type": "text", "parts": [REFUSAL] },
+                    "status": "finished_successfully",
+                    "metadata": { "refusal": true },
+                },
+            }))
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(finish_reason(&body), "content_filter");
+    assert_eq!(content(&body), REFUSAL);
+    assert_eq!(body["choices"][0]["message"]["refusal"], REFUSAL);
+    let chunks = chunks(&server.stream(hello()).await);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "content_filter"
+    );
+
+    // Without the flag, the same answer is a normal one.
+    let upstream = MockUpstream::answer(&[REFUSAL]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server.chat(hello()).await;
+    assert_eq!(finish_reason(&body), "stop");
+    assert!(body["choices"][0]["message"].get("refusal").is_none());
+}
+
+#[tokio::test]
+async fn reports_not_ready_while_draining() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(300)
+            .text("Hello, world!")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server.get("/ready").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let in_flight = server.chat(hello());
+    let drain = async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        server.server.shutting_down.store(true, Ordering::Relaxed);
+        let res = server.get("/ready").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = res.json().await.unwrap();
+        assert_eq!(body["status"], "shutting_down");
+    };
+    let (body, _) = tokio::join!(in_flight, drain);
+    assert_eq!(content(&body), "Hello, world!");
+}
+
+#[tokio::test]
+async fn maps_the_upstream_tool_calls() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .event(json!({
+                "message": {
+                    "author": { "role": "assistant" },
+                    "content": { "content_type": "text", "parts": [""] },
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "function": { "name": "get_weather", "arguments": { "city": "Paris" } },
+                    }],
+                },
+            }))
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let expected = json!([{
+        "index": 0,
+        "id": "call_1",
+        "type": "function",
+        "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" },
+    }]);
+    let body = server.chat(hello()).await;
+    assert_eq!(body["choices"][0]["message"]["tool_calls"], expected);
+    assert_eq!(finish_reason(&body), "tool_calls");
+    let chunks = chunks(&server.stream(hello()).await);
+    let calls: Vec<&Value> = chunks
+        .iter()
+        .filter_map(|v| v["choices"][0]["delta"].get("tool_calls"))
+        .collect();
+    assert_eq!(calls, [&expected]);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "tool_calls"
+    );
+}
+
+#[tokio::test]
+async fn serves_queued_requests_in_order() {
+    let upstream = MockUpstream::start(|req| {
+        let prompt = req.body["messages"][0]["content"]["parts"][0]
+            .as_str()
+            .unwrap();
+        MockResponse::stream().delay(200).text(prompt).done()
+    })
+    .await;
+    let server = TestServer::start(
+        &upstream,
+        &[("MAX_CONCURRENT_REQUESTS", "1"), ("MAX_QUEUE_DEPTH", "2")],
+    )
+    .await;
+    let finished = Mutex::new(vec![]);
+    let request = |prompt: &'static str, after: u64| {
+        let (server, finished) = (&server, &finished);
+        async move {
+            tokio::time::sleep(std::time::Duration::from_millis(after)).await;
+            let res = server
+                .post(
+                    "/v1/chat/completions",
+                    &json!({ "messages": [{ "role": "user", "content": prompt }] }),
+                )
+                .send()
+                .await
+                .unwrap();
+            let status = res.status();
+            let body: Value = res.json().await.unwrap();
+            finished.lock().unwrap().push(content(&body).to_string());
+            status
+        }
+    };
+    let statuses = tokio::join!(
+        request("first", 0),
+        request("second", 50),
+        request("third", 100),
+        request("fourth", 150),
+    );
+    assert_eq!(
+        statuses,
+        (
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::SERVICE_UNAVAILABLE
+        )
+    );
+    // The fourth is refused at once, the others finish in submission order.
+    assert_eq!(*finished.lock().unwrap(), ["", "first", "second", "third"]);
+}
+
+#[tokio::test]
+async fn disables_proxy_buffering_of_streams() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "x-accel-buffering"), Some("no"));
+    assert_eq!(header(&res, "cache-control"), Some("no-cache"));
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "x-accel-buffering"), None);
+}
+
+#[tokio::test]
+async fn reads_compressed_bodies_within_the_limit() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let gzip = |data: &[u8]| {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    };
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("MAX_BODY_SIZE", "1000")]).await;
+    let send = |body: Vec<u8>, encoding: Option<&'static str>| {
+        let mut req = server
+            .client
+            .post(server.url("/v1/chat/completions"))
+            .header("Content-Type", "application/json")
+            .body(body);
+        if let Some(encoding) = encoding {
+            req = req.header("Content-Encoding", encoding);
+        }
+        async move {
+            let res = req.send().await.unwrap();
+            (res.status(), res.json::<Value>().await.unwrap())
+        }
+    };
+
+    let (_, body) = send(gzip(hello().to_string().as_bytes()), Some("gzip")).await;
+    assert_eq!(content(&body), "Hi");
+    assert_eq!(
+        upstream.conversations()[0].body["messages"][0]["content"]["parts"][0],
+        "hi"
+    );
+
+    let (_, body) = send(b"not gzip".to_vec(), Some("gzip")).await;
+    let message = body["error"]["message"].as_str().unwrap();
+    assert!(
+        message.starts_with("Invalid gzip request body"),
+        "{message}"
+    );
+
+    // Too large as sent, and too large once decompressed.
+    let large = json!({ "messages": [{ "role": "user", "content": "a".repeat(2000) }] });
+    for (body, encoding) in [
+        (large.to_string().into_bytes(), None),
+        (gzip(large.to_string().as_bytes()), Some("gzip")),
+    ] {
+        let (status, body) = send(body, encoding).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{encoding:?}");
+        assert_eq!(body["error"]["message"], "Request body exceeds 1000 bytes");
+    }
+    assert_eq!(upstream.conversations().len(), 1);
+}
+
+#[tokio::test]
+async fn adds_the_debug_object_when_allowed_and_asked() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "text/plain",
+            "upstream is down",
+        )
+    })
+    .await;
+    for (flag, asked, expected) in [
+        ("true", true, true),
+        ("true", false, false),
+        ("false", true, false),
+    ] {
+        let server = TestServer::start(&upstream, &[("DEBUG_HEADER", flag)]).await;
+        let mut req = server.post("/v1/chat/completions", &hello());
+        if asked {
+            req = req.header("X-Debug", "1");
+        }
+        let body: Value = req.send().await.unwrap().json().await.unwrap();
+        assert!(body["error"].is_object());
+        assert_eq!(body.get("debug").is_some(), expected, "{flag} {asked}");
+        if expected {
+            let debug = &body["debug"];
+            assert_eq!(debug["upstream_status"], 500);
+            assert_eq!(debug["upstream_body"], "upstream is down");
+            assert_eq!(debug["seed"], "0.42");
+            assert_eq!(debug["difficulty"], "0fffff");
+            assert!(debug["proof_iterations"].as_u64().unwrap() > 0);
+            assert!(debug["timing"]["total_ms"].is_u64());
+            assert!(!body.to_string().contains("gAAAAAB"), "{body}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn gives_up_connecting_after_the_connect_timeout() {
+    // A listener that never accepts: once its backlog is full, further connects hang.
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut backlog = vec![];
+    for _ in 0..4 {
+        let stream = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            tokio::net::TcpStream::connect(addr),
+        )
+        .await;
+        match stream {
+            Ok(Ok(stream)) => backlog.push(stream),
+            _ => break,
+        }
+    }
+    let url = format!("http://{addr}");
+    let server =
+        TestServer::start_with(&[("UPSTREAM_BASE_URL", &url), ("CONNECT_TIMEOUT_SECS", "1")]).await;
+    let started = std::time::Instant::now();
+    let body = server.chat(hello()).await;
+    let elapsed = started.elapsed();
+    assert_eq!(body["status"], false, "{body}");
+    assert!(elapsed >= std::time::Duration::from_secs(1), "{elapsed:?}");
+    assert!(elapsed < std::time::Duration::from_secs(5), "{elapsed:?}");
+}
+
+#[tokio::test]
+async fn connects_over_the_configured_ip_family() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let url = upstream.url().replace("127.0.0.1", "localhost");
+    let server =
+        TestServer::start_with(&[("UPSTREAM_BASE_URL", &url), ("UPSTREAM_IP_FAMILY", "ipv4")])
+            .await;
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+
+    let server =
+        TestServer::start_with(&[("UPSTREAM_BASE_URL", &url), ("UPSTREAM_IP_FAMILY", "ipv6")])
+            .await;
+    let body = server.chat(hello()).await;
+    assert_eq!(body["status"], false, "{body}");
+}
+
+#[tokio::test]
+async fn isolates_the_failures_of_a_batch() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let batch = json!([hello(), {"model": "gpt-3.5-turbo", "messages": []}]);
+    let res = server
+        .post("/v1/chat/completions/batch", &batch)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    let results = body.as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(content(&results[0]), "Hi");
+    assert_eq!(results[1]["error"]["type"], "invalid_request_error");
+    assert_eq!(upstream.conversations().len(), 1);
+
+    let server = TestServer::start(&upstream, &[("MAX_BATCH_SIZE", "1")]).await;
+    let body: Value = server
+        .post("/v1/chat/completions/batch", &batch)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("Too many requests"));
+}
+
+#[tokio::test]
+async fn pushes_the_usage_to_the_webhook() {
+    let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let webhook = MockUpstream::start_with(move |_| {
+        // Fail the first delivery to exercise the retry.
+        match counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+            0 => MockResponse::new(StatusCode::SERVICE_UNAVAILABLE, "text/plain", ""),
+            _ => MockResponse::json(json!({})),
+        }
+    })
+    .await;
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let url = webhook.url();
+    let server = TestServer::start(&upstream, &[("USAGE_WEBHOOK", &url)]).await;
+    let mut body = hello();
+    body["user"] = "alice".into();
+    let res = server.chat(body).await;
+    assert_eq!(content(&res), "Hi");
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while webhook.requests().len() < 2 && std::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let requests = webhook.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].body, requests[1].body);
+    let payload = &requests[1].body;
+    assert_eq!(payload["id"], res["id"]);
+    assert!(payload["request_id"].is_string());
+    assert_eq!(payload["model"], "gpt-3.5-turbo");
+    assert_eq!(payload["user"], "alice");
+    assert!(payload["usage"]["total_tokens"].is_u64());
+    assert_eq!(payload["completion_chars"], 2);
+    assert!(payload["latency_ms"].is_u64());
+    assert_eq!(payload["success"], true);
+    assert_eq!(payload["error"], Value::Null);
+}
+
+#[tokio::test]
+async fn starts_the_answer_with_the_prompt_on_echo() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["echo"] = true.into();
+    assert_eq!(content(&server.chat(body.clone()).await), "hiHi");
+    assert_eq!(streamed_content(&server.stream(body).await), "hiHi");
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+}
+
+#[tokio::test]
+async fn refuses_oversized_frames_before_they_end() {
+    let frame = format!("data: {}", "x".repeat(4096));
+    let upstream = MockUpstream::start(move |req| {
+        let res = MockResponse::stream();
+        let res = match req.body["messages"][0]["content"]["parts"][0].as_str() {
+            Some("partial") => res.text("Hi"),
+            _ => res,
+        };
+        // The frame never ends, only the limit can stop the read.
+        res.raw(&frame).delay(60_000)
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("MAX_FRAME_SIZE", "1024")]).await;
+
+    let started = std::time::Instant::now();
+    let body = server.chat(hello()).await;
+    let message = body["error"]["message"].as_str().unwrap();
+    assert!(
+        message.contains("exceeding the limit of 1024 bytes"),
+        "{body}"
+    );
+
+    let partial = json!({ "messages": [{ "role": "user", "content": "partial" }] });
+    let body = server.chat(partial.clone()).await;
+    assert_eq!(content(&body), "Hi");
+    assert_eq!(finish_reason(&body), "length");
+    let data = server.stream(partial).await;
+    assert_eq!(streamed_content(&data), "Hi");
+    assert_eq!(data.last().unwrap(), "[DONE]");
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn starts_the_ids_with_the_configured_prefix() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("COMPLETION_ID_PREFIX", "gw1-")]).await;
+    let id = server.chat(hello()).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(id.starts_with("gw1-") && id.len() == 20, "{id}");
+    let chunks = chunks(&server.stream(hello()).await);
+    assert!(chunks
+        .iter()
+        .all(|v| v["id"].as_str().unwrap().starts_with("gw1-")));
+
+    let server = TestServer::start(&upstream, &[]).await;
+    let id = server.chat(hello()).await["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(id.starts_with("chatcmpl-"), "{id}");
+
+    let err = Config::from_vars(&[("COMPLETION_ID_PREFIX", "has space")]).unwrap_err();
+    assert!(err.to_string().contains("$COMPLETION_ID_PREFIX"), "{err}");
+}
+
+#[tokio::test]
+async fn answers_with_the_raw_text_when_asked() {
+    let upstream = MockUpstream::answer(&["Hello", "Hello world"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+
+    for (accept, path) in [
+        ("text/plain", "/v1/chat/completions"),
+        ("application/json", "/v1/chat/completions?raw=1"),
+    ] {
+        let res = server
+            .post(path, &hello())
+            .header("Accept", accept)
+            .send()
+            .await
+            .unwrap();
+        assert!(header(&res, "content-type")
+            .unwrap()
+            .starts_with("text/plain"));
+        assert_eq!(res.text().await.unwrap(), "Hello world");
+    }
+
+    let mut body = hello();
+    body["stream"] = true.into();
+    let mut res = server
+        .post("/v1/chat/completions?raw=1", &body)
+        .send()
+        .await
+        .unwrap();
+    assert!(header(&res, "content-type")
+        .unwrap()
+        .starts_with("text/plain"));
+    let mut parts = vec![];
+    while let Some(chunk) = res.chunk().await.unwrap() {
+        parts.push(String::from_utf8(chunk.to_vec()).unwrap());
+    }
+    assert_eq!(parts.concat(), "Hello world");
+    assert!(!parts.concat().contains("data:"));
+
+    // The usual Accept of HTTP libraries keeps the OpenAI envelope.
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .header("Accept", "application/json, text/plain, */*")
+        .send()
+        .await
+        .unwrap();
+    assert!(header(&res, "content-type")
+        .unwrap()
+        .starts_with("application/json"));
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(content(&body), "Hello world");
+}
+
+#[tokio::test]
+async fn passes_the_tool_results_as_context() {
+    let upstream = MockUpstream::answer(&["It is sunny"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let body = server
+        .chat(json!({
+            "messages": [
+                { "role": "user", "content": "What is the weather?" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" },
+                    }],
+                },
+                { "role": "tool", "tool_call_id": "call_1", "name": "get_weather", "content": "sunny" },
+            ],
+        }))
+        .await;
+    assert_eq!(content(&body), "It is sunny", "{body}");
+    let prompt = upstream.conversations()[0].body["messages"].to_string();
+    assert!(
+        prompt.contains(r#"Called get_weather with {\"city\":\"Paris\"}"#),
+        "{prompt}"
+    );
+    assert!(
+        prompt.contains(r"Result of the get_weather call:\nsunny"),
+        "{prompt}"
+    );
+}
+
+#[tokio::test]
+async fn streams_only_the_final_answer_when_asked() {
+    let upstream = MockUpstream::answer(&["Hel", "Hello", "Hello world"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .header("X-Final-Only", "1")
+        .send()
+        .await
+        .unwrap();
+    let data = sse_data(&res.text().await.unwrap());
+    let deltas: Vec<Value> = chunks(&data)
+        .into_iter()
+        .filter(|v| {
+            v["choices"][0]["delta"]["content"]
+                .as_str()
+                .is_some_and(|v| !v.is_empty())
+        })
+        .collect();
+    assert_eq!(deltas.len(), 1, "{data:?}");
+    assert_eq!(streamed_content(&data), "Hello world");
+    assert_eq!(
+        chunks(&data).last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hello world");
+    assert!(chunks(&data).len() > 3, "{data:?}");
+
+    // The upstream correcting itself, the client only sees the corrected text.
+    let upstream = MockUpstream::answer(&["Hel", "Help", "Hello world", "Hello world!"]).await;
+    let server = TestServer::start(&upstream, &[("RESPONSE_PREFIX", "> ")]).await;
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .header("X-Final-Only", "1")
+        .send()
+        .await
+        .unwrap();
+    let data = sse_data(&res.text().await.unwrap());
+    assert_eq!(streamed_content(&data), "> Hello world!");
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .header("X-Final-Only", "1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(content(&res.json().await.unwrap()), "> Hello world!");
+}
+
+#[test]
+fn solves_the_known_proof_of_work_vector() {
+    // A fixed screen size, the built-in one is picked at random.
+    let format = ProofFormat {
+        payload_template: Some(r#"[3000,"{datetime}",{heap_size},{nonce},"{user_agent}"]"#.into()),
+        ..Default::default()
+    };
+    let (found, cancel) = (AtomicBool::new(false), AtomicBool::new(false));
+    let datetime = "Mon Jan 01 2024 00:00:00 GMT+0000 (Coordinated Universal Time)";
+    let (nonce, base) = search_proof(
+        &format,
+        SELFTEST_SEED,
+        datetime,
+        SELFTEST_DIFFICULTY,
+        0..POW_MAX_ITERATIONS,
+        &found,
+        &cancel,
+    )
+    .unwrap();
+    // Pinned, a change means the payload or the hashing changed.
+    assert_eq!(nonce, 35);
+    assert_eq!(
+        base,
+        STANDARD.encode(proof_payload(&format, datetime, nonce))
+    );
+    assert!(meets_difficulty(
+        &format,
+        SELFTEST_SEED,
+        &base,
+        SELFTEST_DIFFICULTY
+    ));
+    assert!(proof_self_test(&format, 1));
+}
+
+#[tokio::test]
+async fn names_the_message_with_ambiguous_content() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let cases = [
+        (
+            json!({ "type": "text", "text": "hi" }),
+            "messages[1].content must be a string",
+        ),
+        (
+            json!([{ "type": "text", "text": "a" }, { "type": "text", "text": "b" }]),
+            "messages[1].content has 2 parts",
+        ),
+        (
+            json!([{ "type": "image_url" }]),
+            "messages[1].content[0] must be a text part",
+        ),
+    ];
+    for (content, expected) in cases {
+        let body = server
+            .chat(json!({
+                "messages": [
+                    { "role": "system", "content": "Be brief" },
+                    { "role": "user", "content": content },
+                ],
+            }))
+            .await;
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains(expected), "{message}");
+    }
+    assert!(upstream.conversations().is_empty());
+}
+
+#[test]
+fn reads_the_client_ip_only_from_trusted_proxies() {
+    let server =
+        Server::new(Config::from_vars(&[("TRUSTED_PROXIES", "10.0.0.0/8")]).unwrap()).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.2".parse().unwrap());
+    let ip = |v: &str| v.parse::<std::net::IpAddr>().unwrap();
+
+    // The rightmost untrusted hop is the client.
+    assert_eq!(
+        server.client_ip(ip("10.0.0.1"), &headers),
+        ip("203.0.113.7")
+    );
+    // An untrusted peer could have forged the headers.
+    assert_eq!(
+        server.client_ip(ip("198.51.100.1"), &headers),
+        ip("198.51.100.1")
+    );
+    // IPv4 peers accepted on an IPv6 socket are still trusted.
+    assert_eq!(
+        server.client_ip(ip("::ffff:10.0.0.1"), &headers),
+        ip("203.0.113.7")
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-real-ip", "203.0.113.8".parse().unwrap());
+    assert_eq!(
+        server.client_ip(ip("10.0.0.1"), &headers),
+        ip("203.0.113.8")
+    );
+    assert_eq!(
+        server.client_ip(ip("198.51.100.1"), &headers),
+        ip("198.51.100.1")
+    );
+
+    let server = Server::new(Config::from_vars(&[]).unwrap()).unwrap();
+    assert_eq!(server.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+}
+
+#[tokio::test]
+async fn serves_the_fallback_message_while_the_circuit_is_open() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", "down")
+    })
+    .await;
+    let server = TestServer::start(
+        &upstream,
+        &[
+            ("CIRCUIT_BREAKER_THRESHOLD", "1"),
+            ("CIRCUIT_BREAKER_COOLDOWN_SECS", "60"),
+            ("FALLBACK_ENABLED", "true"),
+            ("FALLBACK_MESSAGE", "Back soon"),
+        ],
+    )
+    .await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert!(header(&res, "x-fallback").is_none());
+    let body: Value = res.json().await.unwrap();
+    assert!(body["error"].is_object(), "{body}");
+
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "x-fallback"), Some("1"));
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["object"], "chat.completion");
+    assert_eq!(content(&body), "Back soon");
+    assert_eq!(finish_reason(&body), "stop");
+
+    let mut body = hello();
+    body["stream"] = true.into();
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, "x-fallback"), Some("1"));
+    assert_eq!(
+        streamed_content(&sse_data(&res.text().await.unwrap())),
+        "Back soon"
+    );
+    assert_eq!(upstream.conversations().len(), 1);
+}
+
+#[tokio::test]
+async fn echoes_the_request_metadata() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["metadata"] = json!({ "session": "abc", "tier": "free" });
+    body["store"] = true.into();
+    let res = server.chat(body).await;
+    assert_eq!(content(&res), "Hi");
+    assert_eq!(res["metadata"], json!({ "session": "abc", "tier": "free" }));
+
+    assert!(server.chat(hello()).await.get("metadata").is_none());
+
+    let mut body = hello();
+    body["metadata"] = "abc".into();
+    let res = server.chat(body).await;
+    assert!(res["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("'metadata'"));
+}
+
+#[tokio::test]
+async fn caps_and_reports_the_upstream_bytes() {
+    let upstream = MockUpstream::answer(&["a", "ab", "abc", "abcd", "abcde", "abcdef"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    let total: usize = header(&res, "x-upstream-bytes").unwrap().parse().unwrap();
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(content(&body), "abcdef");
+
+    // Stops reading halfway through the answer.
+    let max = total / 2;
+    let server = TestServer::start(&upstream, &[("MAX_UPSTREAM_BYTES", &max.to_string())]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    let bytes: usize = header(&res, "x-upstream-bytes").unwrap().parse().unwrap();
+    assert!(bytes > max && bytes < total, "{bytes} {max} {total}");
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(finish_reason(&body), "length");
+    assert!(content(&body).len() < 6, "{body}");
+
+    let data = server.stream(hello()).await;
+    assert!(streamed_content(&data).len() < 6);
+    assert_eq!(
+        chunks(&data).last().unwrap()["choices"][0]["finish_reason"],
+        "length"
+    );
+}
+
+#[test]
+fn names_the_fields_violating_the_schema() {
+    let errors = schema::validate_chat_request(&json!({
+        "model": 3,
+        "temperature": 3.5,
+        "maxTokens": 10,
+        "messages": [
+            { "role": "user", "content": "hi" },
+            { "role": "assistent", "content": "hello" },
+            { "role": "user", "content": [{ "type": "text" }] },
+            { "role": "tool", "content": "sunny" },
+        ],
+    }));
+    assert_eq!(
+        errors,
+        [
+            "model: expected a string, found 3",
+            "temperature: expected a number between 0 and 2, found 3.5",
+            "maxTokens: unknown field, did you mean 'max_tokens'?",
+            "messages[1].role: unknown variant 'assistent', expected one of system, user, assistant, tool, function",
+            "messages[2].content[0].text: expected a string",
+            "messages[3].tool_call_id: missing field",
+        ]
+    );
+    assert_eq!(
+        schema::validate_chat_request(&json!({ "model": "gpt-3.5-turbo" })),
+        ["messages: missing field"]
+    );
+    assert!(schema::validate_chat_request(&hello()).is_empty());
+}
+
+#[tokio::test]
+async fn validates_the_schema_only_when_strict() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let mut body = hello();
+    body["temperature"] = 5.into();
+    let server = TestServer::start(&upstream, &[("STRICT_SCHEMA", "1")]).await;
+    let res = server.chat(body.clone()).await;
+    assert_eq!(
+        res["error"]["message"],
+        "Invalid request, temperature: expected a number between 0 and 2, found 5"
+    );
+    assert!(upstream.conversations().is_empty());
+
+    let server = TestServer::start(&upstream, &[]).await;
+    assert_eq!(content(&server.chat(body).await), "Hi");
+}
+
+#[tokio::test]
+async fn sends_the_system_prompt_of_the_header() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("SYSTEM_PROMPT", "Be brief")]).await;
+    let body = json!({
+        "messages": [
+            { "role": "system", "content": "Use emojis" },
+            { "role": "user", "content": "hi" },
+        ],
+    });
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .header("X-System-Prompt", "Answer in French")
+        .send()
+        .await
+        .unwrap();
+    let res: Value = res.json().await.unwrap();
+    assert_eq!(content(&res), "Hi");
+    let sent = upstream.conversations()[0].body["messages"].to_string();
+    assert!(
+        sent.contains(r"Be brief\n\nAnswer in French\n\nUse emojis"),
+        "{sent}"
+    );
+
+    let server = TestServer::start(&upstream, &[]).await;
+    server
+        .post("/v1/chat/completions", &hello())
+        .header("X-System-Prompt", "Answer in French")
+        .send()
+        .await
+        .unwrap();
+    let sent = upstream.conversations()[1].body["messages"].to_string();
+    assert!(sent.contains("Answer in French"), "{sent}");
+    assert!(!sent.contains("Be brief"), "{sent}");
+}
+
+#[tokio::test]
+async fn refuses_rapid_reconnects_from_one_address() {
+    let server = TestServer::start_with(&[("MAX_CONNECTIONS_PER_IP", "2")]).await;
+    // Without pooling every request opens a new connection.
+    let client = Client::builder()
+        .no_proxy()
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap();
+    for _ in 0..2 {
+        let res = client.get(server.url("/v1/models")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    assert!(client.get(server.url("/v1/models")).send().await.is_err());
+
+    let limiter =
+        connection_limiter::ConnectionLimiter::new(2, std::time::Duration::from_millis(50));
+    let ip = |v: &str| v.parse::<std::net::IpAddr>().unwrap();
+    assert!(limiter.allow(ip("10.0.0.1")));
+    assert!(limiter.allow(ip("10.0.0.1")));
+    assert!(!limiter.allow(ip("10.0.0.1")));
+    assert!(limiter.allow(ip("10.0.0.2")));
+    tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+    assert!(limiter.allow(ip("10.0.0.1")));
+}
+
+#[tokio::test]
+async fn checks_the_answer_against_the_json_schema() {
+    let answers = Arc::new(Mutex::new(vec![]));
+    let pending = answers.clone();
+    let upstream = MockUpstream::start(move |_| {
+        let answer: String = pending.lock().unwrap().remove(0);
+        MockResponse::stream().text(&answer).done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["response_format"] = json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "person",
+            "schema": {
+                "type": "object",
+                "properties": { "name": { "type": "string" } },
+                "required": ["name"],
+            },
+        },
+    });
+    let conforming = "```json\n{\"name\": \"Ann\"}\n```".to_string();
+    let wrong = "{\"name\": 3}".to_string();
+
+    *answers.lock().unwrap() = vec![conforming.clone()];
+    let res = server.chat(body.clone()).await;
+    assert_eq!(content(&res), "{\"name\": \"Ann\"}");
+    let prompt = upstream.conversations()[0].body["messages"].to_string();
+    assert!(
+        prompt.contains("conforms to the following JSON Schema"),
+        "{prompt}"
+    );
+
+    // A wrong answer is asked for once more.
+    *answers.lock().unwrap() = vec![wrong.clone(), conforming];
+    let data = server.stream(body.clone()).await;
+    assert_eq!(streamed_content(&data), "{\"name\": \"Ann\"}");
+    assert_eq!(upstream.conversations().len(), 3);
+
+    *answers.lock().unwrap() = vec![wrong.clone(), wrong];
+    let res = server.chat(body).await;
+    assert_eq!(res["error"]["type"], "server_error");
+    let message = res["error"]["message"].as_str().unwrap();
+    assert!(
+        message.contains("does not match the json_schema"),
+        "{message}"
+    );
+    assert_eq!(upstream.conversations().len(), 5);
+}
+
+#[test]
+fn solves_the_proof_of_work_with_another_algorithm() {
+    use crate::proof::ProofAlgorithm;
+
+    let hex = |algorithm: ProofAlgorithm| hex_encode(&algorithm.hash(b"abc"));
+    assert!(hex(ProofAlgorithm::Sha256).starts_with("ba7816bf"));
+    assert!(hex(ProofAlgorithm::Sha3_256).starts_with("3a985da7"));
+    assert!(hex(ProofAlgorithm::Sha512).starts_with("ddaf35a1"));
+    assert!(hex(ProofAlgorithm::Sha3_512).starts_with("b751850b"));
+
+    let config = Config::from_vars(&[("POW_ALGORITHM", "SHA-256")]).unwrap();
+    let format = config.pow_format;
+    assert_eq!(format.algorithm, ProofAlgorithm::Sha256);
+    let cancel = AtomicBool::new(false);
+    let (seed, diff) = (SELFTEST_SEED, SELFTEST_DIFFICULTY);
+    let (token, _) = calculate_proof_token("test", &format, seed, diff, 1, &cancel).unwrap();
+    let base = token.strip_prefix(POW_TOKEN_PREFIX).unwrap();
+    assert!(meets_difficulty(&format, seed, base, diff));
+
+    assert!(Config::from_vars(&[("POW_ALGORITHM", "md5")]).is_err());
+}
+
+#[tokio::test]
+async fn adds_the_token_timing_only_with_the_debug_flag() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(50)
+            .text("Hello world")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("DEBUG_HEADER", "true")]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .header("X-Debug", "1")
+        .send()
+        .await
+        .unwrap();
+    let chunks = chunks(&sse_data(&res.text().await.unwrap()));
+    let elapsed: Vec<u64> = chunks
+        .iter()
+        .map(|v| v["x_timing"]["elapsed_ms"].as_u64().unwrap())
+        .collect();
+    assert!(elapsed.windows(2).all(|v| v[0] <= v[1]), "{elapsed:?}");
+    assert!(
+        elapsed.last().unwrap() - elapsed.first().unwrap() >= 50,
+        "{elapsed:?}"
+    );
+
+    let data = server.stream(hello()).await;
+    assert!(chunks_without_timing(&data));
+
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &body)
+        .header("X-Debug", "1")
+        .send()
+        .await
+        .unwrap();
+    assert!(chunks_without_timing(&sse_data(&res.text().await.unwrap())));
+}
+
+fn chunks_without_timing(data: &[String]) -> bool {
+    chunks(data).iter().all(|v| v.get("x_timing").is_none())
+}
+
+#[tokio::test]
+async fn rotates_through_the_device_ids() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("OAI_DEVICE_IDS", "dev-a, dev-b")]).await;
+    for _ in 0..3 {
+        assert_eq!(content(&server.chat(hello()).await), "Hi");
+    }
+    let device_ids: Vec<String> = upstream
+        .requests()
+        .iter()
+        .map(|v| v.headers["oai-device-id"].to_str().unwrap().to_string())
+        .collect();
+    // The requirements and the conversation of a request share the device id.
+    assert_eq!(
+        device_ids,
+        ["dev-a", "dev-a", "dev-b", "dev-b", "dev-a", "dev-a"]
+    );
+
+    let server = TestServer::start(&upstream, &[]).await;
+    server.chat(hello()).await;
+    server.chat(hello()).await;
+    let requests = upstream.requests();
+    let device_id = |i: usize| requests[i].headers["oai-device-id"].to_str().unwrap();
+    assert_eq!(device_id(6), device_id(7));
+    assert_ne!(device_id(6), device_id(8));
+}
+
+#[tokio::test]
+async fn streams_the_frames_as_they_arrive() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(300)
+            .text("Hello world")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let mut res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.version(), reqwest::Version::HTTP_11);
+    assert_eq!(header(&res, "transfer-encoding"), Some("chunked"));
+    assert!(header(&res, "content-length").is_none());
+    assert!(header(&res, "connection").is_none());
+
+    let started = std::time::Instant::now();
+    let mut text = String::new();
+    let mut arrivals = vec![];
+    while let Some(chunk) = res.chunk().await.unwrap() {
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+        if text.ends_with("\n\n") {
+            arrivals.push((started.elapsed(), streamed_content(&sse_data(&text))));
+        }
+    }
+    let first = arrivals.iter().find(|v| v.1 == "Hello").unwrap().0;
+    let second = arrivals.iter().find(|v| v.1 == "Hello world").unwrap().0;
+    assert!(
+        second - first >= std::time::Duration::from_millis(250),
+        "{arrivals:?}"
+    );
+}
+
+#[tokio::test]
+async fn offers_only_the_allowed_tls_versions() {
+    assert_eq!(client_hello_versions(&[]).await, [0x0304, 0x0303]);
+    assert_eq!(
+        client_hello_versions(&[("UPSTREAM_MIN_TLS", "1.2")]).await,
+        [0x0304, 0x0303]
+    );
+    assert_eq!(
+        client_hello_versions(&[("UPSTREAM_MIN_TLS", "tls1.3")]).await,
+        [0x0304]
+    );
+    assert!(Config::from_vars(&[("UPSTREAM_MIN_TLS", "1.1")]).is_err());
+}
+
+/// The TLS versions offered by the upstream client, read from the `supported_versions` extension
+/// of its ClientHello.
+async fn client_hello_versions(vars: &[(&str, &str)]) -> Vec<u16> {
+    use tokio::io::AsyncReadExt;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    let mut vars = vars.to_vec();
+    vars.push(("UPSTREAM_BASE_URL", &url));
+    let server = TestServer::start_with(&vars).await;
+    let (hello, _) = tokio::join!(
+        async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut hello = vec![0; 5];
+            stream.read_exact(&mut hello).await.unwrap();
+            let len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+            hello.resize(5 + len, 0);
+            stream.read_exact(&mut hello[5..]).await.unwrap();
+            hello
+        },
+        server.chat(hello()),
+    );
+    let u16_at = |i: usize| u16::from_be_bytes([hello[i], hello[i + 1]]) as usize;
+    // The record and handshake headers, the legacy version and the random.
+    let mut i = 5 + 4 + 2 + 32;
+    i += 1 + hello[i] as usize;
+    i += 2 + u16_at(i);
+    i += 1 + hello[i] as usize;
+    let end = i + 2 + u16_at(i);
+    i += 2;
+    while i < end {
+        let (kind, len) = (u16_at(i), u16_at(i + 2));
+        if kind == 0x002b {
+            let versions = &hello[i + 5..i + 5 + hello[i + 4] as usize];
+            return versions
+                .chunks(2)
+                .map(|v| u16::from_be_bytes([v[0], v[1]]))
+                .collect();
+        }
+        i += 4 + len;
+    }
+    panic!("no supported_versions extension");
+}
+
+#[tokio::test]
+async fn shares_one_completion_between_identical_requests() {
+    let upstream =
+        MockUpstream::start(|_| MockResponse::stream().delay(300).text("Hi").done()).await;
+    let server = TestServer::start(&upstream, &[("SINGLE_FLIGHT", "true")]).await;
+    let (leader, follower) = tokio::join!(server.chat(hello()), async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        server.stream(hello()).await
+    });
+    assert_eq!(content(&leader), "Hi");
+    assert_eq!(streamed_content(&follower), "Hi");
+    assert_eq!(upstream.conversations().len(), 1);
+
+    // Once finished, the completion is not shared with later requests.
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    assert_eq!(upstream.conversations().len(), 2);
+}
+
+#[tokio::test]
+async fn serves_azure_style_requests() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let vars = [("AUTHORIZATION", "Bearer secret"), ("AZURE_COMPAT", "true")];
+    let server = TestServer::start(&upstream, &vars).await;
+    let path = "/openai/deployments/gpt-4o/chat/completions?api-version=2024-02-01";
+    let res = server
+        .post(path, &hello())
+        .header("api-key", "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(content(&body), "Hi");
+
+    let res = server
+        .post(path, &hello())
+        .header("api-key", "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // Without the setting, neither the route nor the header is known.
+    let server = TestServer::start(&upstream, &vars[..1]).await;
+    let res = server
+        .post(path, &hello())
+        .header("api-key", "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = server
+        .post(path, &hello())
+        .header("Authorization", "Bearer secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn resumes_without_repeating_or_garbling_the_answer() {
+    let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = attempts.clone();
+    let upstream = MockUpstream::start(move |_| {
+        match counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed) {
+            0 | 2 => MockResponse::stream().text("Hello").delay(20).cut(),
+            1 => MockResponse::answer(&["Hello", "Hello, world!"]),
+            _ => MockResponse::answer(&["Goodbye", "Goodbye, world!"]),
+        }
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("AUTO_RESUME", "true")]).await;
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hello, world!");
+
+    // A resumed answer that differs ends the answer with what was already sent.
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hello");
+    let finish = chunks(&data).last().unwrap()["choices"][0]["finish_reason"].clone();
+    assert_eq!(finish, "stop");
+    assert_eq!(upstream.conversations().len(), 4);
+}
+
+#[tokio::test]
+async fn keeps_the_frame_order_across_the_transforms() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hel")
+            .text("Hello")
+            .delay(30)
+            .text("Hello, wor")
+            .text("Hello, world!")
+            .done()
+    })
+    .await;
+    let vars = [
+        ("RESPONSE_PREFIX", ">> "),
+        ("RESPONSE_SUFFIX", " <<"),
+        ("COALESCE_CHARS", "4"),
+        ("MIN_FRAME_INTERVAL_MS", "10"),
+    ];
+    let server = TestServer::start(&upstream, &vars).await;
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), ">> Hello, world! <<");
+    assert_eq!(data.iter().filter(|v| *v == "[DONE]").count(), 1);
+    assert_eq!(data.last().unwrap(), "[DONE]");
+    let chunks = chunks(&data);
+    let roles: Vec<usize> = (0..chunks.len())
+        .filter(|&i| !chunks[i]["choices"][0]["delta"]["role"].is_null())
+        .collect();
+    assert_eq!(roles, [0]);
+    let (last, content) = chunks.split_last().unwrap();
+    assert_eq!(last["choices"][0]["finish_reason"], "stop");
+    assert!(last["usage"].is_object());
+    for chunk in content {
+        assert!(chunk["choices"][0]["finish_reason"].is_null(), "{chunk}");
+        assert!(chunk.get("usage").is_none(), "{chunk}");
+    }
+}
+
+#[tokio::test]
+async fn serves_the_routes_under_the_base_path() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("BASE_PATH", "/api/chatgpt/")]).await;
+    let res = server
+        .post("/api/chatgpt/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(content(&res.json().await.unwrap()), "Hi");
+    let res = server.get("/api/chatgpt/v1/models").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    for path in ["/v1/models", "/api/chatgptv1/models", "/api/v1/models"] {
+        let res = server.get(path).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+
+    // Without a base path the routes are at the root.
+    let server = TestServer::start(&upstream, &[]).await;
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    let res = server.get("/api/chatgpt/v1/models").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert!(Config::from_vars(&[("BASE_PATH", "api")]).is_err());
+}
+
+#[tokio::test]
+async fn reports_interrupted_streams_as_errors_when_asked() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .text("Hello, wor")
+            .delay(20)
+            .cut()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("STREAM_ERROR_EVENTS", "true")]).await;
+    let data = server.stream(hello()).await;
+    assert_eq!(data.last().unwrap(), "[DONE]");
+    let events: Vec<Value> = data[..data.len() - 1]
+        .iter()
+        .map(|v| serde_json::from_str(v).unwrap())
+        .collect();
+    let (error, chunks) = events.split_last().unwrap();
+    let message = error["error"]["message"].as_str().unwrap();
+    assert!(message.contains("the answer is incomplete"), "{message}");
+    let text: String = chunks
+        .iter()
+        .filter_map(|v| v["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(text, "Hello, wor");
+    for chunk in chunks {
+        assert!(chunk["choices"][0]["finish_reason"].is_null(), "{chunk}");
+    }
+}
+
+#[tokio::test]
+async fn accepts_string_and_numeric_booleans() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    for stream in [json!("true"), json!(" TRUE "), json!(1)] {
+        let mut body = hello();
+        body["stream"] = stream.clone();
+        let res = server
+            .post("/v1/chat/completions", &body)
+            .send()
+            .await
+            .unwrap();
+        let data = sse_data(&res.text().await.unwrap());
+        assert_eq!(streamed_content(&data), "Hi", "{stream}");
+    }
+    for stream in [json!("false"), json!(0), json!(2)] {
+        let mut body = hello();
+        body["stream"] = stream.clone();
+        assert_eq!(content(&server.chat(body).await), "Hi", "{stream}");
+    }
+}
+
+#[tokio::test]
+async fn defers_connections_beyond_the_maximum() {
+    use tokio::{io::AsyncWriteExt, net::TcpStream, time::Duration};
+
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let server = TestServer::start(&upstream, &[("MAX_CONNECTIONS", "1")]).await;
+    let mut held = TcpStream::connect(server.addr).await.unwrap();
+    held.write_all(b"GET /v1/models HTTP/1.1\r\nHost: x\r\n\r\n")
+        .await
+        .unwrap();
+    let res = tokio::time::timeout(
+        Duration::from_millis(300),
+        server.post("/v1/chat/completions", &hello()).send(),
+    )
+    .await;
+    assert!(res.is_err(), "answered past the connection limit");
+
+    // Once the connection closes, the deferred one is served.
+    drop(held);
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+}
+
+#[tokio::test]
+async fn reports_upstream_streams_of_only_empty_messages() {
+    let upstream = MockUpstream::start(|_| MockResponse::answer(&["", "", ""])).await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "upstream produced no content, only 3 empty assistant messages"
+    );
+
+    let data = server.stream(hello()).await;
+    let error: Value = serde_json::from_str(&data[data.len() - 2]).unwrap();
+    assert_eq!(
+        error["error"]["message"],
+        "upstream produced no content, only 3 empty assistant messages"
+    );
+    assert_eq!(data.last().unwrap(), "[DONE]");
+}
+
+#[tokio::test]
+async fn rewrites_the_prompt_with_the_hook() {
+    let upstream = MockUpstream::answer(&["Hi"]).await;
+    let hook = r#"sed 's/"prompt":"\([^"]*\)"/"prompt":"\U\1"/'"#;
+    let server = TestServer::start(&upstream, &[("PROMPT_HOOK", hook)]).await;
+    let body = json!({ "messages": [{ "role": "user", "content": "hello there" }] });
+    assert_eq!(content(&server.chat(body).await), "Hi");
+    let sent = upstream.conversations()[0].body["messages"].to_string();
+    assert!(sent.contains("HELLO THERE"), "{sent}");
+
+    // A prompt filling the pipes, passed through a command writing while it reads.
+    let server = TestServer::start(&upstream, &[("PROMPT_HOOK", "tr x y")]).await;
+    let prompt = "x".repeat(256 * 1024);
+    let body = json!({ "messages": [{ "role": "user", "content": prompt }] });
+    assert_eq!(content(&server.chat(body).await), "Hi");
+    let sent = upstream.conversations().last().unwrap().body["messages"].to_string();
+    assert!(
+        sent.contains(&"y".repeat(256 * 1024)),
+        "the hook was skipped"
+    );
+
+    // A failing or slow hook leaves the prompt as it was.
+    for hook in ["exit 1", "sleep 5"] {
+        let vars = [("PROMPT_HOOK", hook), ("PROMPT_HOOK_TIMEOUT_SECS", "1")];
+        let server = TestServer::start(&upstream, &vars).await;
+        let body = json!({ "messages": [{ "role": "user", "content": "hello there" }] });
+        assert_eq!(content(&server.chat(body).await), "Hi");
+        let sent = upstream.conversations().last().unwrap().body["messages"].to_string();
+        assert!(sent.contains("hello there"), "{hook}: {sent}");
+    }
+}
+
+#[tokio::test]
+async fn streams_the_prompt_filter_results_only_when_asked() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .event(json!({ "moderation_response": { "flagged": false, "blocked": false } }))
+            .text("Hi")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hi");
+    for chunk in chunks(&data) {
+        assert_eq!(chunk["choices"].as_array().unwrap().len(), 1, "{chunk}");
+    }
+    let body = server.chat(hello()).await;
+    assert!(body["prompt_filter_results"].is_array(), "{body}");
+
+    let server = TestServer::start(&upstream, &[("PROMPT_FILTER_RESULTS", "true")]).await;
+    let data = server.stream(hello()).await;
+    assert_eq!(streamed_content(&data), "Hi");
+    let filters: Vec<Value> = chunks(&data)
+        .into_iter()
+        .filter(|v| v["choices"] == json!([]))
+        .collect();
+    assert!(!filters.is_empty(), "{data:?}");
+    for filter in filters {
+        let results = &filter["prompt_filter_results"][0]["content_filter_results"];
+        assert_eq!(results["hate"]["severity"], "safe", "{filter}");
+    }
+}
+
+#[tokio::test]
+async fn sends_the_role_delta_before_the_requirements() {
+    let upstream = MockUpstream::start_with(|req| match req.path.as_str() {
+        crate::CHAT_REQUIREMENTS_PATH => MockResponse::requirements().delay(500),
+        _ => MockResponse::answer(&["Hi"]),
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("EARLY_ROLE_FRAME", "true")]).await;
+    let mut body = hello();
+    body["stream"] = true.into();
+    let start = std::time::Instant::now();
+    let mut res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    let chunk = res.chunk().await.unwrap().unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_millis(400));
+    let first = chunks(&sse_data(&String::from_utf8_lossy(&chunk)));
+    assert_eq!(first[0]["choices"][0]["delta"]["role"], "assistant");
+    let mut text = String::from_utf8_lossy(&chunk).to_string();
+    while let Some(chunk) = res.chunk().await.unwrap() {
+        text.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let data = sse_data(&text);
+    assert_eq!(streamed_content(&data), "Hi");
+    let roles = chunks(&data)
+        .iter()
+        .filter(|v| !v["choices"][0]["delta"]["role"].is_null())
+        .count();
+    assert_eq!(roles, 1);
+
+    // A client leaving during the proof of work cancels the completion.
+    let mut res = server
+        .post("/v1/chat/completions", &body)
+        .send()
+        .await
+        .unwrap();
+    res.chunk().await.unwrap().unwrap();
+    drop(res);
+    tokio::time::sleep(std::time::Duration::from_millis(800)).await;
+    assert_eq!(upstream.conversations().len(), 1);
+}
+
+#[tokio::test]
+async fn accepts_the_keys_of_the_authorization_file() {
+    let path = std::env::temp_dir().join(format!("chatgpt-free-api-keys-{}", std::process::id()));
+    std::fs::write(&path, "Bearer first\n\n  Bearer second  \n").unwrap();
+    let path = path.to_str().unwrap();
+    let vars = [
+        ("AUTHORIZATION_FILE", path),
+        ("AUTHORIZATION", "Bearer ignored"),
+    ];
+    let server = TestServer::start_with(&vars).await;
+    for (authorization, status) in [
+        ("Bearer first", StatusCode::OK),
+        ("Bearer second", StatusCode::OK),
+        ("Bearer ignored", StatusCode::UNAUTHORIZED),
+        ("Bearer", StatusCode::UNAUTHORIZED),
+    ] {
+        let res = server
+            .get("/v1/models")
+            .header("Authorization", authorization)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), status, "{authorization}");
+    }
+
+    // An empty or missing file leaves no key, which must not open the API.
+    std::fs::write(path, "\n \n").unwrap();
+    assert!(Config::from_vars(&[("AUTHORIZATION_FILE", path)]).is_err());
+    std::fs::remove_file(path).unwrap();
+    assert!(Config::from_vars(&[("AUTHORIZATION_FILE", path)]).is_err());
+}
+
+#[tokio::test]
+async fn counts_the_unexpected_upstream_frames() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .event(json!({ "message": { "content": { "parts": ["Hi"] } } }))
+            .data("not json")
+            .text("Hi")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[]).await;
+    let ready: Value = server
+        .get("/ready")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(ready["unexpected_frames"], 0);
+    assert_eq!(content(&server.chat(hello()).await), "Hi");
+    let ready: Value = server
+        .get("/ready")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(ready["unexpected_frames"], 2);
+}
+
+#[tokio::test]
+async fn answers_with_the_partial_content_at_the_deadline() {
+    let upstream = MockUpstream::start(|_| {
+        MockResponse::stream()
+            .text("Hello")
+            .delay(3000)
+            .text("Hello, world!")
+            .done()
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("REQUEST_DEADLINE", "1")]).await;
+    let start = std::time::Instant::now();
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_millis(2500));
+    assert_eq!(header(&res, "x-deadline-hit"), Some("1"));
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(content(&body), "Hello");
+    assert_eq!(finish_reason(&body), "length");
+
+    // Before any content, the deadline is a timeout.
+    let upstream = MockUpstream::start_with(|req| match req.path.as_str() {
+        crate::CHAT_REQUIREMENTS_PATH => MockResponse::requirements().delay(3000),
+        _ => MockResponse::answer(&["Hi"]),
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("REQUEST_DEADLINE", "1")]).await;
+    let res = server
+        .post("/v1/chat/completions", &hello())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "timeout");
+}
+
+#[tokio::test]
+async fn finishes_the_shared_completion_when_the_leader_leaves() {
+    use std::time::Duration;
+
+    let upstream = MockUpstream::start_with(|req| match req.path.as_str() {
+        crate::CHAT_REQUIREMENTS_PATH => MockResponse::requirements().delay(500),
+        _ => MockResponse::answer(&["Hello", "Hello, world!"]),
+    })
+    .await;
+    let server = TestServer::start(&upstream, &[("SINGLE_FLIGHT", "true")]).await;
+    let leader = tokio::time::timeout(
+        Duration::from_millis(250),
+        server.post("/v1/chat/completions", &hello()).send(),
+    );
+    let (leader, follower) = tokio::join!(leader, async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.chat(hello()).await
+    });
+    assert!(leader.is_err(), "the leader was answered before leaving");
+    assert_eq!(content(&follower), "Hello, world!");
+    assert_eq!(upstream.conversations().len(), 1);
+
+    // Once every request sharing it left, the completion is dropped.
+    let res = tokio::time::timeout(
+        Duration::from_millis(250),
+        server.post("/v1/chat/completions", &hello()).send(),
+    )
+    .await;
+    assert!(res.is_err());
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    assert_eq!(upstream.conversations().len(), 1);
+}
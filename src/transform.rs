@@ -0,0 +1,372 @@
+use crate::{markdown::MarkdownStripper, ResEvent};
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    time::Instant,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Batch small text deltas into fewer events, flushing once `max_chars` characters are buffered
+/// or `interval` has elapsed since the first buffered delta.
+pub fn coalesce(
+    mut rx: Receiver<ResEvent>,
+    max_chars: Option<usize>,
+    interval: Option<Duration>,
+) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut buffer = String::new();
+        let mut flush_at: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                event = rx.recv() => {
+                    match event {
+                        // An empty delta carries the role and must not be merged.
+                        Some(ResEvent::Text(text)) if !text.is_empty() => {
+                            buffer.push_str(&text);
+                            if flush_at.is_none() {
+                                flush_at = interval.map(|v| Instant::now() + v);
+                            }
+                            if max_chars.is_some_and(|v| buffer.chars().count() >= v) {
+                                flush(&tx, &mut buffer, &mut flush_at).await;
+                            }
+                        }
+                        Some(event) => {
+                            flush(&tx, &mut buffer, &mut flush_at).await;
+                            let _ = tx.send(event).await;
+                        }
+                        None => {
+                            flush(&tx, &mut buffer, &mut flush_at).await;
+                            break;
+                        }
+                    }
+                }
+                _ = crate::sleep_until(flush_at) => {
+                    flush(&tx, &mut buffer, &mut flush_at).await;
+                }
+            }
+        }
+    });
+    new_rx
+}
+
+async fn flush(tx: &Sender<ResEvent>, buffer: &mut String, flush_at: &mut Option<Instant>) {
+    *flush_at = None;
+    if !buffer.is_empty() {
+        let _ = tx.send(ResEvent::Text(std::mem::take(buffer))).await;
+    }
+}
+
+/// Emit text deltas at most once per `min_interval`, buffering the deltas that arrive in between.
+/// Any other event drains the buffer immediately.
+pub fn pace(mut rx: Receiver<ResEvent>, min_interval: Duration) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut buffer = String::new();
+        let mut next_at = Instant::now();
+        let mut flush_at: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                event = rx.recv() => {
+                    match event {
+                        Some(ResEvent::Text(text)) if !text.is_empty() => {
+                            buffer.push_str(&text);
+                            if flush_at.is_none() {
+                                flush_at = Some(next_at.max(Instant::now()));
+                            }
+                        }
+                        Some(event) => {
+                            flush(&tx, &mut buffer, &mut flush_at).await;
+                            let _ = tx.send(event).await;
+                        }
+                        None => {
+                            flush(&tx, &mut buffer, &mut flush_at).await;
+                            break;
+                        }
+                    }
+                }
+                _ = crate::sleep_until(flush_at) => {
+                    flush(&tx, &mut buffer, &mut flush_at).await;
+                    next_at = Instant::now() + min_interval;
+                }
+            }
+        }
+    });
+    new_rx
+}
+
+/// Report a stream the upstream interrupted after some content as an error instead of finishing
+/// it with reason `length`, which clients easily take for a complete answer.
+pub fn fail_interrupted(mut rx: Receiver<ResEvent>) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let ResEvent::Interrupted(err) = event {
+                let err =
+                    format!("The upstream stream was interrupted, the answer is incomplete, {err}");
+                let _ = tx.send(ResEvent::Error(err)).await;
+                break;
+            }
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+    new_rx
+}
+
+/// Enforce the order streaming clients rely on whatever the transforms before did: a single
+/// role delta first, the content, then exactly one finish. A stream that closes without
+/// finishing ends with an error, so the client always gets its `[DONE]`.
+pub fn order(mut rx: Receiver<ResEvent>) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut role_sent = false;
+        while let Some(event) = rx.recv().await {
+            match event {
+                ResEvent::First(_) => continue,
+                ResEvent::Text(ref text) if text.is_empty() && role_sent => continue,
+                ResEvent::Error(_) => {}
+                _ if !role_sent => {
+                    if !matches!(&event, ResEvent::Text(text) if text.is_empty()) {
+                        let _ = tx.send(ResEvent::Text(String::new())).await;
+                    }
+                    role_sent = true;
+                }
+                _ => {}
+            }
+            let finished = matches!(event, ResEvent::Done(_) | ResEvent::Error(_));
+            if tx.send(event).await.is_err() || finished {
+                return;
+            }
+        }
+        let err = "The completion ended without finishing".to_string();
+        let _ = tx.send(ResEvent::Error(err)).await;
+    });
+    new_rx
+}
+
+/// Stop the completion with finish reason `length` when the deadline passes, dropping the
+/// upstream events so the conversation is closed early. `hit` records that it was cut.
+pub fn deadline(
+    mut rx: Receiver<ResEvent>,
+    deadline: Instant,
+    hit: Arc<AtomicBool>,
+) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut text_sent = false;
+        loop {
+            let event = tokio::select! {
+                event = rx.recv() => event,
+                _ = tokio::time::sleep_until(deadline) => {
+                    hit.store(true, Ordering::Relaxed);
+                    let event = if text_sent {
+                        ResEvent::Done("length")
+                    } else {
+                        let err = "The request deadline passed before the upstream sent any content";
+                        ResEvent::Error(err.to_string())
+                    };
+                    let _ = tx.send(event).await;
+                    return;
+                }
+            };
+            let Some(event) = event else {
+                return;
+            };
+            if matches!(&event, ResEvent::Text(text) if !text.is_empty()) {
+                text_sent = true;
+            }
+            let finished = matches!(event, ResEvent::Done(_) | ResEvent::Error(_));
+            if tx.send(event).await.is_err() || finished {
+                return;
+            }
+        }
+    });
+    new_rx
+}
+
+/// Stop the completion with finish reason `length` once `max_chars` characters have been emitted,
+/// dropping the upstream events so the conversation is closed early.
+pub fn truncate(mut rx: Receiver<ResEvent>, max_chars: usize) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut remaining = max_chars;
+        while let Some(event) = rx.recv().await {
+            match event {
+                ResEvent::Text(text) if text.chars().count() > remaining => {
+                    let text = truncate_chars(&text, remaining);
+                    if !text.is_empty() {
+                        let _ = tx.send(ResEvent::Text(text.to_string())).await;
+                    }
+                    let _ = tx.send(ResEvent::Done("length")).await;
+                    break;
+                }
+                ResEvent::Text(text) => {
+                    remaining -= text.chars().count();
+                    let _ = tx.send(ResEvent::Text(text)).await;
+                }
+                event => {
+                    let _ = tx.send(event).await;
+                }
+            }
+        }
+    });
+    new_rx
+}
+
+/// Keep at most `max_chars` characters, cutting between extended grapheme clusters so that
+/// combining marks, emoji modifiers and joined sequences are never split.
+pub fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    let mut chars = 0;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        chars += grapheme.chars().count();
+        if chars > max_chars {
+            return &text[..i];
+        }
+    }
+    text
+}
+
+/// Forward the events unchanged, keeping `guard` alive until the completion is over.
+pub fn hold<T: Send + 'static>(mut rx: Receiver<ResEvent>, guard: T) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let _guard = guard;
+        while let Some(event) = rx.recv().await {
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+    new_rx
+}
+
+/// Remove markdown from text deltas, holding back partial lines so markup is never split.
+pub fn strip_markdown(mut rx: Receiver<ResEvent>) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut stripper = MarkdownStripper::default();
+        let mut buffer = String::new();
+        let mut output = String::new();
+        while let Some(event) = rx.recv().await {
+            match event {
+                ResEvent::Text(text) if !text.is_empty() => {
+                    buffer.push_str(&text);
+                    while let Some(i) = buffer.find('\n') {
+                        let line: String = buffer.drain(..=i).collect();
+                        if let Some(line) = stripper.strip_line(&line[..i]) {
+                            output.push_str(&line);
+                            output.push('\n');
+                        }
+                    }
+                    if !output.is_empty() {
+                        let _ = tx.send(ResEvent::Text(std::mem::take(&mut output))).await;
+                    }
+                }
+                ResEvent::Done(_) | ResEvent::Error(_) => {
+                    if let Some(line) = stripper.strip_line(&std::mem::take(&mut buffer)) {
+                        if !line.is_empty() {
+                            let _ = tx.send(ResEvent::Text(line)).await;
+                        }
+                    }
+                    let _ = tx.send(event).await;
+                }
+                event => {
+                    let _ = tx.send(event).await;
+                }
+            }
+        }
+    });
+    new_rx
+}
+
+/// Emit `prefix` before the first text delta and `suffix` right before the completion finishes.
+pub fn wrap(
+    mut rx: Receiver<ResEvent>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut prefix = prefix;
+        while let Some(event) = rx.recv().await {
+            match event {
+                ResEvent::Text(text) if !text.is_empty() => {
+                    if let Some(prefix) = prefix.take() {
+                        let _ = tx.send(ResEvent::Text(prefix)).await;
+                    }
+                    let _ = tx.send(ResEvent::Text(text)).await;
+                }
+                ResEvent::Done(_) => {
+                    if let Some(prefix) = prefix.take() {
+                        let _ = tx.send(ResEvent::Text(prefix)).await;
+                    }
+                    if let Some(suffix) = &suffix {
+                        let _ = tx.send(ResEvent::Text(suffix.clone())).await;
+                    }
+                    let _ = tx.send(event).await;
+                }
+                event => {
+                    let _ = tx.send(event).await;
+                }
+            }
+        }
+    });
+    new_rx
+}
+
+/// Hold back the text until the completion ends and send it as a single delta, the last
+/// snapshot replacing what the upstream rewrote.
+pub fn settle(mut rx: Receiver<ResEvent>) -> Receiver<ResEvent> {
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut buffer = String::new();
+        while let Some(event) = rx.recv().await {
+            match event {
+                ResEvent::Text(text) if !text.is_empty() => buffer.push_str(&text),
+                ResEvent::Snapshot(text) => buffer = text,
+                event => {
+                    let ends = matches!(event, ResEvent::Done(_) | ResEvent::Error(_));
+                    if ends && !buffer.is_empty() {
+                        let _ = tx.send(ResEvent::Text(std::mem::take(&mut buffer))).await;
+                    }
+                    let _ = tx.send(event).await;
+                }
+            }
+        }
+    });
+    new_rx
+}
+
+/// Call `f` once the completion ends with the number of characters generated, or the error.
+pub fn on_finish<F>(mut rx: Receiver<ResEvent>, f: F) -> Receiver<ResEvent>
+where
+    F: FnOnce(Result<usize, String>) + Send + 'static,
+{
+    let (tx, new_rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut chars = 0;
+        let mut outcome = Err("The completion was interrupted".to_string());
+        while let Some(event) = rx.recv().await {
+            match &event {
+                ResEvent::Text(text) => chars += text.chars().count(),
+                ResEvent::Done(_) => outcome = Ok(chars),
+                ResEvent::Error(err) => outcome = Err(err.clone()),
+                _ => {}
+            }
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+        f(outcome);
+    });
+    new_rx
+}
@@ -0,0 +1,59 @@
+use anyhow::{bail, Result};
+use reqwest::{Client, ClientBuilder};
+use serde_json::Value;
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pushes a usage record per completion to the operator's accounting endpoint.
+#[derive(Debug)]
+pub struct UsageWebhook {
+    client: Client,
+    url: String,
+}
+
+impl UsageWebhook {
+    pub fn new(url: String) -> Result<Self> {
+        let client = ClientBuilder::new().timeout(TIMEOUT).build()?;
+        Ok(Self { client, url })
+    }
+
+    /// Deliver the payload in the background, retrying with exponential backoff.
+    pub fn send(&self, req_id: &str, payload: Value) {
+        let client = self.client.clone();
+        let url = self.url.clone();
+        let req_id = req_id.to_string();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            for attempt in 1..=MAX_ATTEMPTS {
+                match post(&client, &url, &payload).await {
+                    Ok(()) => return,
+                    Err(err) if attempt < MAX_ATTEMPTS => {
+                        debug!(
+                            "[{req_id}] Usage webhook failed, retrying in {}s, {err}",
+                            backoff.as_secs()
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "[{req_id}] Usage webhook failed after {MAX_ATTEMPTS} attempts, {err}"
+                        )
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn post(client: &Client, url: &str, payload: &Value) -> Result<()> {
+    let res = client.post(url).json(payload).send().await?;
+    let status = res.status();
+    if !status.is_success() {
+        bail!("Invalid response code {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Compute the `Sec-WebSocket-Accept` header for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{ACCEPT_GUID}").as_bytes(),
+    );
+    STANDARD.encode(hash.as_ref())
+}
+
+/// The server side of an upgraded WebSocket connection, exchanging text messages only.
+pub struct WebSocket<S> {
+    io: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    pub fn new(io: S) -> Self {
+        Self { io }
+    }
+
+    /// Read the next text message, answering pings on the way. Returns `None` once closed.
+    pub async fn recv(&mut self) -> Result<Option<String>> {
+        let mut message = vec![];
+        loop {
+            let (fin, opcode, payload) = self.read_frame().await?;
+            match opcode {
+                OPCODE_TEXT | OPCODE_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if message.len() > MAX_MESSAGE_SIZE {
+                        bail!("WebSocket message exceeds {MAX_MESSAGE_SIZE} bytes");
+                    }
+                    if fin {
+                        return Ok(Some(String::from_utf8(message)?));
+                    }
+                }
+                OPCODE_BINARY => bail!("WebSocket binary messages are not supported"),
+                OPCODE_CLOSE => {
+                    let _ = self.write_frame(OPCODE_CLOSE, &payload).await;
+                    return Ok(None);
+                }
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &payload).await?,
+                OPCODE_PONG => {}
+                _ => bail!("Unknown WebSocket opcode {opcode}"),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, text: &str) -> Result<()> {
+        self.write_frame(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    async fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        self.io.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => self.io.read_u16().await? as usize,
+            127 => self.io.read_u64().await? as usize,
+            v => v as usize,
+        };
+        if len > MAX_MESSAGE_SIZE {
+            bail!("WebSocket frame exceeds {MAX_MESSAGE_SIZE} bytes");
+        }
+        // Clients must mask every frame they send.
+        if !masked {
+            bail!("Received an unmasked WebSocket frame");
+        }
+        let mut mask = [0u8; 4];
+        self.io.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; len];
+        self.io.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.io.write_all(&frame).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+}